@@ -9,12 +9,13 @@ use burn::{
 };
 use clap::{Parser, ValueEnum};
 use craft_burn::{
-    image_util::{
-        float_to_color_map, resize_aspect_ratio, NormalizeMeanVarianceConfig, ResizeResult,
-    },
+    heatmap::score_images,
+    image_util::{float_to_color_map, PreprocessConfig, ResizeResult},
     loader,
+    npy::save_npz,
     refine::{RefineNet, RefineNetRecord},
-    utils::{adjust_coordinates, get_det_boxes},
+    utils::{adjust_coordinates, get_detections, ScoreCalibration},
+    visualize::{visualize, OverlayConfig},
     Craft, CraftRecord,
 };
 use image::{DynamicImage, Rgb};
@@ -61,6 +62,9 @@ pub struct Args {
     /// Path to refiner weights
     #[arg(long, default_value = "weights/craft_refiner_CTW1500.mpk")]
     refiner_model: PathBuf,
+    /// Save the network outputs to `<image>_maps.npz` to replay the post-processing later
+    #[arg(long)]
+    dump_maps: bool,
 
     /// Convert pytorch weights to mpk
     #[arg(long)]
@@ -130,21 +134,20 @@ pub fn test_net<B: Backend>(
     link_threshold: f64,
     low_text: f64,
     out_dir: &Path,
+    dump_maps: bool,
     device: &B::Device,
 ) {
     let mut image_out = image.to_rgb8();
     let total_start = Instant::now();
-    let ResizeResult::<B> { image, ratio, .. } = resize_aspect_ratio(image, 1280, 1.5, device);
-
-    let ratio_h = 1.0 / ratio;
-    let ratio_w = ratio_h;
+    let preprocess = PreprocessConfig::new().init(device);
+    let ResizeResult::<B> {
+        image: x,
+        transform,
+        ..
+    } = preprocess.forward(&image);
 
     let start = Instant::now();
 
-    let norm_mean_variance = NormalizeMeanVarianceConfig::new().init(device);
-
-    let x = norm_mean_variance.forward(image);
-
     let (y, feature) = net.forward(x);
 
     let score_text = y.clone().narrow(3, 0, 1);
@@ -159,11 +162,19 @@ pub fn test_net<B: Backend>(
 
     if let Some(refine_net) = refine_net {
         let start = Instant::now();
-        score_link = refine_net.forward(y.clone(), feature).narrow(3, 0, 1);
+        score_link = refine_net
+            .forward(y.clone(), feature.clone())
+            .narrow(3, 0, 1);
         let _ = score_link.to_data();
         println!("RefineNet took {:?}", Instant::now() - start);
     }
 
+    if dump_maps {
+        let path = out_dir.join(format!("{img_name}_maps.npz"));
+        let outputs = vec![("y", y), ("feature", feature), ("link", score_link.clone())];
+        save_npz(path, outputs).unwrap();
+    }
+
     let image_text = float_to_color_map(score_text.clone());
     let image_link = float_to_color_map(score_link.clone());
 
@@ -175,24 +186,30 @@ pub fn test_net<B: Backend>(
         .unwrap();
 
     let start = Instant::now();
-    let boxes = get_det_boxes(
+    let detections = get_detections(
         score_text.clone(),
         score_link.clone(),
         text_threshold,
         link_threshold,
         low_text,
+        &ScoreCalibration::new(),
     );
 
-    let boxes = adjust_coordinates(boxes, ratio_w, ratio_h);
+    let detections = adjust_coordinates(detections, &transform);
     println!("Processing time: {:?}", Instant::now() - start);
     println!("Total time: {:?}", Instant::now() - total_start);
 
-    for bbox in boxes.values() {
-        draw_hollow_polygon_mut(&mut image_out, bbox, Rgb([255, 0, 0]));
+    for detection in detections.values() {
+        draw_hollow_polygon_mut(&mut image_out, &detection.bbox, Rgb([255, 0, 0]));
     }
     DynamicImage::ImageRgb8(image_out)
         .save(out_dir.join(format!("{img_name}_boxes.png")))
         .unwrap();
+
+    let scores = score_images(score_text, score_link, &transform);
+    visualize(&image, &scores, &detections, &OverlayConfig::new())
+        .save(out_dir.join(format!("{img_name}_overlay.png")))
+        .unwrap();
 }
 
 pub fn run<B: Backend>(device: &B::Device, mut args: Args) {
@@ -238,6 +255,7 @@ pub fn run<B: Backend>(device: &B::Device, mut args: Args) {
         args.link_threshold,
         args.low_text,
         &args.out_dir,
+        args.dump_maps,
         device,
     );
     test_net(
@@ -249,6 +267,7 @@ pub fn run<B: Backend>(device: &B::Device, mut args: Args) {
         args.link_threshold,
         args.low_text,
         &args.out_dir,
+        args.dump_maps,
         device,
     );
 }
@@ -1,27 +1,252 @@
 use std::collections::HashMap;
 
-use burn::{prelude::Backend, tensor::Tensor};
-use connected::{connected_components_with_stats, ConnectedComponentsResult};
-use float_ord::FloatOrd;
-use image::{GenericImageView, GrayImage, ImageBuffer, Luma};
+use burn::{config::Config, prelude::Backend, tensor::Tensor};
+use connected::{
+    label_and_read_back, quantized_threshold, DeviceComponentsResult, LabelledMaps, Stats,
+    SCORE_SCALE,
+};
+use image::{GrayImage, ImageBuffer, Luma};
 use imageproc::{
-    contours::find_contours, definitions::Image, distance_transform::Norm, geometry::min_area_rect,
-    morphology::dilate_mut, point::Point, region_labelling::Connectivity,
+    contours::{find_contours, BorderType, Contour},
+    distance_transform::Norm,
+    geometry::min_area_rect,
+    morphology::dilate_mut,
+    point::Point,
+    rect::Rect,
+    region_labelling::Connectivity,
 };
 use rayon::prelude::*;
+use thresholds::{adaptive_thresholds, AdaptiveThresholdConfig, Thresholds};
 
-pub fn adjust_coordinates(
-    mut boxes: HashMap<u32, [Point<f32>; 4]>,
-    ratio_w: f32,
-    ratio_h: f32,
-) -> HashMap<u32, [Point<f32>; 4]> {
-    for bbox in boxes.values_mut().flatten() {
-        bbox.x *= ratio_w * 2.0;
-        bbox.y *= ratio_h * 2.0;
+use crate::{
+    geometry::{area, distance, signed_area},
+    image_util::ImageTransform,
+};
+
+pub mod characters;
+pub mod connected;
+pub mod hysteresis;
+pub mod mask;
+pub mod thresholds;
+
+/// Map boxes from heatmap coordinates back to the original image, clipped to its bounds
+pub fn adjust_coordinates<T: AsMut<[Point<f32>]>>(
+    mut boxes: HashMap<u32, T>,
+    transform: &ImageTransform,
+) -> HashMap<u32, T> {
+    for point in boxes.values_mut().flat_map(|b| b.as_mut()) {
+        *point = transform.clip(transform.to_image(*point));
     }
     boxes
 }
 
+/// A detected word with its confidence
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Detection {
+    pub bbox: [Point<f32>; 4],
+    pub confidence: Confidence,
+}
+
+impl AsMut<[Point<f32>]> for Detection {
+    fn as_mut(&mut self) -> &mut [Point<f32>] {
+        &mut self.bbox
+    }
+}
+
+/// Confidence metrics of a detection, measured on the score maps
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Confidence {
+    /// Highest region score in the segment
+    pub max_text: f32,
+    /// Mean region score over the character pixels of the segment
+    pub mean_text: f32,
+    /// Mean link score over the link pixels of the segment, or 0 for words without links
+    pub mean_link: f32,
+    /// Fraction of the pixels in the box with a region score of at least `low_text`
+    pub coverage: f32,
+    /// Combination of the other metrics, see [`ScoreCalibration`]
+    pub score: f32,
+}
+
+/// Logistic combination of the confidence metrics into a single score in `[0, 1]`.
+///
+/// The default weights and bias are picked by hand rather than fitted to any data set. With them,
+/// a detection with every metric at 0.5 scores about 0.4, and a clean word with a `max_text` of
+/// 0.9, a `mean_text` of 0.8, no links and full coverage about 0.9. Use [`fit`](Self::fit) to
+/// calibrate them to a model and data set.
+#[derive(Config, Debug)]
+pub struct ScoreCalibration {
+    /// Weights of `max_text`, `mean_text`, `mean_link` and `coverage`
+    #[config(default = "[2.0, 4.0, 1.0, 2.0]")]
+    weights: [f32; 4],
+    #[config(default = "-5.0")]
+    bias: f32,
+}
+
+/// L2 penalty on the weights when fitting, which keeps them finite on separable data
+const CALIBRATION_L2: f32 = 1e-3;
+
+impl ScoreCalibration {
+    pub fn apply(&self, c: &Confidence) -> f32 {
+        let logit: f32 = self
+            .weights
+            .iter()
+            .zip(features(c))
+            .map(|(w, f)| w * f)
+            .sum::<f32>()
+            + self.bias;
+        1.0 / (1.0 + (-logit).exp())
+    }
+
+    /// Fit the weights and bias with logistic regression on detections labelled as true or false,
+    /// e.g. by matching them against ground truth boxes. Runs `epochs` steps of full batch
+    /// gradient descent, starting from `self`.
+    pub fn fit(&self, samples: &[(Confidence, bool)], epochs: usize, learning_rate: f32) -> Self {
+        let mut fitted = self.clone();
+        if samples.is_empty() {
+            return fitted;
+        }
+
+        let n = samples.len() as f32;
+        for _ in 0..epochs {
+            let mut weights = fitted.weights.map(|w| CALIBRATION_L2 * w);
+            let mut bias = 0.0;
+            for (confidence, label) in samples {
+                let error = fitted.apply(confidence) - *label as u8 as f32;
+                for (g, f) in weights.iter_mut().zip(features(confidence)) {
+                    *g += error * f / n;
+                }
+                bias += error / n;
+            }
+            for (w, g) in fitted.weights.iter_mut().zip(weights) {
+                *w -= learning_rate * g;
+            }
+            fitted.bias -= learning_rate * bias;
+        }
+        fitted
+    }
+}
+
+fn features(c: &Confidence) -> [f32; 4] {
+    [c.max_text, c.mean_text, c.mean_link, c.coverage]
+}
+
+/// How word segments are grown from the score maps
+#[derive(Config, Debug, Copy, PartialEq, Eq)]
+pub enum Segmentation {
+    /// Components of the pixels above `low_text` or `link_threshold`, kept if they reach
+    /// `text_threshold`
+    Threshold,
+    /// Hysteresis from seeds above `text_threshold`, see
+    /// [`get_detections_hysteresis`](hysteresis::get_detections_hysteresis)
+    Hysteresis,
+}
+
+/// Serializable version of [`Connectivity`]
+#[derive(Config, Debug, Copy, PartialEq, Eq)]
+pub enum ComponentConnectivity {
+    Four,
+    Eight,
+}
+
+/// Where the components of the score maps are labelled
+#[derive(Config, Debug, Copy, PartialEq, Eq)]
+pub enum Labelling {
+    /// Label on the device, so only the score maps and the compact stats are read back, see
+    /// [`connected_components_device`](connected::connected_components_device)
+    Device,
+    /// Read the score maps back and label them on the CPU, in parallel strips for large maps.
+    /// Usually faster on CPU backends, where the iterative device labelling is slow.
+    Cpu,
+}
+
+impl From<ComponentConnectivity> for Connectivity {
+    fn from(connectivity: ComponentConnectivity) -> Self {
+        match connectivity {
+            ComponentConnectivity::Four => Connectivity::Four,
+            ComponentConnectivity::Eight => Connectivity::Eight,
+        }
+    }
+}
+
+/// Serializable version of [`Norm`], the shape of the dilation kernel
+#[derive(Config, Debug, Copy, PartialEq, Eq)]
+pub enum DilationNorm {
+    L1,
+    L2,
+    LInf,
+}
+
+impl From<DilationNorm> for Norm {
+    fn from(norm: DilationNorm) -> Self {
+        match norm {
+            DilationNorm::L1 => Norm::L1,
+            DilationNorm::L2 => Norm::L2,
+            DilationNorm::LInf => Norm::LInf,
+        }
+    }
+}
+
+/// Which contour of a dilated segment the box is fitted to
+#[derive(Config, Debug, Copy, PartialEq, Eq)]
+pub enum ContourSelection {
+    /// The first contour found, in raster order
+    First,
+    /// The outer contour enclosing the largest area
+    Largest,
+}
+
+/// Parameters of the whole post-processing, from score maps to boxes. The defaults match
+/// [`get_det_boxes`].
+#[derive(Config, Debug)]
+pub struct PostProcessConfig {
+    #[config(default = "Thresholds::new()")]
+    thresholds: Thresholds,
+    /// Derive the thresholds from each image instead of using `thresholds`
+    #[config(default = "None")]
+    adaptive: Option<AdaptiveThresholdConfig>,
+    #[config(default = "Segmentation::Threshold")]
+    segmentation: Segmentation,
+    #[config(default = "ComponentConnectivity::Four")]
+    connectivity: ComponentConnectivity,
+    #[config(default = "Labelling::Device")]
+    labelling: Labelling,
+    /// Segments with fewer pixels are dropped
+    #[config(default = 10)]
+    min_area: u32,
+    /// Segments are dilated by `dilation_offset + dilation_scale * sqrt(area * min(w, h) / (w * h))`
+    /// pixels, rounded down, where `w` and `h` are the size of their bounding box
+    #[config(default = 1)]
+    dilation_offset: u32,
+    #[config(default = 1.0)]
+    dilation_scale: f32,
+    #[config(default = "DilationNorm::L1")]
+    dilation_norm: DilationNorm,
+    #[config(default = "ContourSelection::First")]
+    contour: ContourSelection,
+    /// Boxes whose aspect ratio is within this of 1 are replaced by their axis aligned bounds
+    #[config(default = "SQUARE_TOLERANCE")]
+    square_tolerance: f32,
+    #[config(default = "ScoreCalibration::new()")]
+    calibration: ScoreCalibration,
+}
+
+impl PostProcessConfig {
+    /// The fixed thresholds, or the ones derived from the maps in adaptive mode
+    fn resolve_thresholds<B: Backend>(
+        &self,
+        text_map: &Tensor<B, 4>,
+        link_map: &Tensor<B, 4>,
+    ) -> Thresholds {
+        match &self.adaptive {
+            Some(adaptive) => adaptive_thresholds(text_map.clone(), link_map.clone(), adaptive),
+            None => self.thresholds,
+        }
+    }
+}
+
+const SQUARE_TOLERANCE: f32 = 0.1;
+
 type FloatGrayImage = ImageBuffer<Luma<f32>, Vec<f32>>;
 
 pub fn get_det_boxes<B: Backend>(
@@ -31,282 +256,385 @@ pub fn get_det_boxes<B: Backend>(
     link_threshold: f64,
     low_text: f64,
 ) -> HashMap<u32, [Point<f32>; 4]> {
-    let shape = text_map.shape().dims::<4>();
-    let [_, height, width, _] = shape;
+    get_detections(
+        text_map,
+        link_map,
+        text_threshold,
+        link_threshold,
+        low_text,
+        &ScoreCalibration::new(),
+    )
+    .into_iter()
+    .map(|(k, detection)| (k, detection.bbox))
+    .collect()
+}
 
-    let text_score = text_map.clone().greater_equal_elem(low_text).int();
-    let link_score = link_map.clone().greater_equal_elem(link_threshold).int();
+/// Same as [`get_det_boxes`], but also measures the confidence of each detection
+pub fn get_detections<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    text_threshold: f64,
+    link_threshold: f64,
+    low_text: f64,
+    calibration: &ScoreCalibration,
+) -> HashMap<u32, Detection> {
+    let config = PostProcessConfig::new()
+        .with_thresholds(Thresholds {
+            text_threshold,
+            link_threshold,
+            low_text,
+        })
+        .with_calibration(calibration.clone());
+    post_process(text_map, link_map, &config).1
+}
 
-    let combined = (text_score.clone() + link_score.clone()).clamp(0, 1);
-    let data = combined.to_data().convert::<u8>().to_vec::<u8>().unwrap();
-    let text_score_comb = GrayImage::from_vec(width as u32, height as u32, data).unwrap();
+/// Same as [`get_detections`], but with thresholds derived from the score maps of this image.
+/// Returns the thresholds that were used along with the detections.
+pub fn get_detections_adaptive<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    config: &AdaptiveThresholdConfig,
+    calibration: &ScoreCalibration,
+) -> (Thresholds, HashMap<u32, Detection>) {
+    let config = PostProcessConfig::new()
+        .with_adaptive(Some(config.clone()))
+        .with_calibration(calibration.clone());
+    post_process(text_map, link_map, &config)
+}
+
+/// Detect words with every post-processing parameter taken from `config`. Returns the thresholds
+/// that were used along with the detections.
+pub fn post_process<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    config: &PostProcessConfig,
+) -> (Thresholds, HashMap<u32, Detection>) {
+    let thresholds = config.resolve_thresholds(&text_map, &link_map);
+    let detections = match config.segmentation {
+        Segmentation::Threshold => segment(text_map, link_map, &thresholds, config),
+        Segmentation::Hysteresis => hysteresis::segment(text_map, link_map, &thresholds, config),
+    };
+    (thresholds, detections)
+}
 
-    let ConnectedComponentsResult {
+fn segment<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    thresholds: &Thresholds,
+    config: &PostProcessConfig,
+) -> HashMap<u32, Detection> {
+    let Some(LabelledMaps {
+        maps, components, ..
+    }) = label_and_read_back(
+        text_map,
+        link_map,
+        thresholds,
+        thresholds.low_text,
+        config.connectivity.into(),
+        config.labelling,
+        false,
+    )
+    else {
+        return HashMap::new();
+    };
+    let DeviceComponentsResult {
         stats,
+        max_score,
+        seeds,
         num_labels,
-        labels,
-    } = connected_components_with_stats(&text_score_comb, Connectivity::Four, Luma([0]));
-    let text_map_data = text_map.into_data().convert::<f32>().to_vec::<f32>();
-    let text_map =
-        FloatGrayImage::from_vec(width as u32, height as u32, text_map_data.unwrap()).unwrap();
-
-    let text_score = text_score.into_data().convert::<u8>().to_vec::<u8>();
-    let text_score = GrayImage::from_vec(width as u32, height as u32, text_score.unwrap()).unwrap();
-    let link_score = link_score.into_data().convert::<u8>().to_vec::<u8>();
-    let link_score = GrayImage::from_vec(width as u32, height as u32, link_score.unwrap()).unwrap();
-
-    let boxes = unsafe {
-        (1..num_labels)
-            .into_par_iter()
-            .filter_map(|k| {
-                let size = stats.area[k as usize];
-                if size < 10 {
-                    return None;
-                }
+    } = components;
+    let (width, height) = maps.text_score.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    let text_threshold = quantized_threshold(thresholds.text_threshold);
+
+    (1..num_labels)
+        .into_par_iter()
+        .filter_map(|k| {
+            let k = k as usize;
+            if stats.area[k] < config.min_area || max_score[k] < text_threshold {
+                return None;
+            }
 
-                let max = text_map.enumerate_pixels().filter_map(|(x, y, p)| {
-                    (labels.unsafe_get_pixel(x, y) == Luma([k])).then_some(FloatOrd(p.0[0]))
-                });
-                let max = max.max().unwrap().0;
+            let (roi, radius) = dilation_roi(&stats, k, (width, height), config);
+            let (seg_map, segment) = seg_map(seeds[k], roi, &maps, config.connectivity.into());
+            detection(seg_map, roi, radius, &segment, &maps, config).map(|d| (k as u32, d))
+        })
+        .collect()
+}
 
-                if max < text_threshold as f32 {
-                    return None;
-                }
+/// Bounding box of component `k`, grown one pixel past the radius it is dilated with, and that
+/// radius. The margin keeps the dilated mask off the edge of the ROI, so it has a contour.
+fn dilation_roi(
+    stats: &Stats,
+    k: usize,
+    (width, height): (usize, usize),
+    config: &PostProcessConfig,
+) -> (Rect, u32) {
+    let size = stats.area[k];
+    let x = stats.left[k];
+    let y = stats.top[k];
+    let w = stats.right[k] - x;
+    let h = stats.bottom[k] - y;
+
+    let niter = config.dilation_scale * (size as f32 * w.min(h) as f32 / (w * h) as f32).sqrt();
+    let radius = config.dilation_offset + niter as u32;
+
+    // Only process the bounding box of the component, grown by the dilation radius and a margin
+    let margin = radius + 1;
+    let left = x.saturating_sub(margin);
+    let top = y.saturating_sub(margin);
+    let right = (x + w + margin).min(width as u32 - 1);
+    let bottom = (y + h + margin).min(height as u32 - 1);
+    let roi = Rect::at(left as i32, top as i32).of_size(right - left + 1, bottom - top + 1);
+    (roi, radius)
+}
 
-                let mut seg_map = seg_map(&labels, k, &text_score, &link_score);
-                let x = stats.left[k as usize];
-                let y = stats.top[k as usize];
-                let w = stats.right[k as usize] - x;
-                let h = stats.bottom[k as usize] - y;
-
-                let niter = ((size as f32 * w.min(h) as f32 / (w * h) as f32).sqrt()) as u32;
-                dilate_mut(&mut seg_map, Norm::L1, (1 + niter) as u8);
-
-                let contours = find_contours::<i32>(&seg_map);
-                let [a, b, c, d] = min_area_rect(&contours[0].points);
-
-                let as_float = |p: Point<i32>| Point {
-                    x: p.x as f32,
-                    y: p.y as f32,
-                };
-
-                let [a, b, c, d] = [as_float(a), as_float(b), as_float(c), as_float(d)];
-
-                let width = norm(a - b);
-                let height = norm(b - c);
-                let box_ratio = width.max(height) / (width.min(height) + 1e-5);
-                let bbox = if (1.0 - box_ratio).abs() <= 0.1 {
-                    let l = a.x.min(b.x).min(c.x).min(d.x);
-                    let r = a.x.max(b.x).max(c.x).max(d.x);
-                    let t = a.y.min(b.y).min(c.y).min(d.y);
-                    let b = a.y.max(b.y).max(c.y).max(d.y);
-                    [p(l, t), p(r, t), p(r, b), p(l, b)]
-                } else {
-                    [a, b, c, d]
-                };
-
-                Some((k, bbox))
-            })
-            .collect()
+/// Dilate the mask of a segment covering `roi`, fit a box to its outline and measure its
+/// confidence. `None` if the dilated mask has no contour, which happens when it fills the whole
+/// image.
+fn detection(
+    mut seg_map: GrayImage,
+    roi: Rect,
+    radius: u32,
+    segment: &Segment,
+    maps: &ScoreMaps,
+    config: &PostProcessConfig,
+) -> Option<Detection> {
+    dilate_mut(
+        &mut seg_map,
+        config.dilation_norm.into(),
+        radius.min(u8::MAX as u32) as u8,
+    );
+
+    let contours = find_contours::<i32>(&seg_map);
+    let contour = match config.contour {
+        ContourSelection::First => contours.first()?,
+        ContourSelection::Largest => contours
+            .iter()
+            .filter(|c| c.border_type == BorderType::Outer)
+            .max_by(|a, b| contour_area(a).total_cmp(&contour_area(b)))
+            .or(contours.first())?,
+    };
+    // Back to image coordinates before fitting, as `min_area_rect` rounds its corners towards 0
+    let points: Vec<_> = contour
+        .points
+        .iter()
+        .map(|p| Point::new(p.x + roi.left(), p.y + roi.top()))
+        .collect();
+    let [a, b, c, d] = min_area_rect(&points);
+
+    let as_float = |p: Point<i32>| Point {
+        x: p.x as f32,
+        y: p.y as f32,
     };
 
-    boxes
+    let bbox = align_square(
+        [as_float(a), as_float(b), as_float(c), as_float(d)],
+        config.square_tolerance,
+    );
+    let mut confidence = Confidence {
+        max_text: segment.max_text,
+        mean_text: segment.text_sum / segment.text_count.max(1) as f32,
+        mean_link: segment.link_sum / segment.link_count.max(1) as f32,
+        coverage: maps.coverage(&bbox),
+        score: 0.0,
+    };
+    confidence.score = config.calibration.apply(&confidence);
+    Some(Detection { bbox, confidence })
 }
 
-fn p(x: f32, y: f32) -> Point<f32> {
-    Point { x, y }
+fn contour_area(contour: &Contour<i32>) -> f32 {
+    let points: Vec<_> = contour
+        .points
+        .iter()
+        .map(|q| p(q.x as f32, q.y as f32))
+        .collect();
+    area(&points)
 }
 
-fn norm(point: Point<f32>) -> f32 {
-    (point.x.abs().sqrt() + point.y.abs().sqrt()).sqrt()
+/// Replace boxes whose aspect ratio is within `tolerance` of 1 with their axis aligned bounding box
+fn align_square([a, b, c, d]: [Point<f32>; 4], tolerance: f32) -> [Point<f32>; 4] {
+    let width = distance(a, b);
+    let height = distance(b, c);
+    let box_ratio = width.max(height) / (width.min(height) + 1e-5);
+    if (1.0 - box_ratio).abs() <= tolerance {
+        let l = a.x.min(b.x).min(c.x).min(d.x);
+        let r = a.x.max(b.x).max(c.x).max(d.x);
+        let t = a.y.min(b.y).min(c.y).min(d.y);
+        let b = a.y.max(b.y).max(c.y).max(d.y);
+        [p(l, t), p(r, t), p(r, b), p(l, b)]
+    } else {
+        [a, b, c, d]
+    }
 }
 
-fn seg_map(
-    labels: &Image<Luma<u32>>,
-    group: u32,
-    text_score: &GrayImage,
-    link_score: &GrayImage,
-) -> GrayImage {
-    let mut out = GrayImage::new(labels.width(), labels.height());
-    let inputs = labels.iter().zip(text_score.iter()).zip(link_score.iter());
-    for (((g, text), link), p) in inputs.zip(out.iter_mut()) {
-        let link_only = *text == 0 && *link == 1;
-        *p = if *g == group && !link_only { 255 } else { 0 };
-    }
-    out
+fn p(x: f32, y: f32) -> Point<f32> {
+    Point { x, y }
 }
 
-mod connected {
-    use std::cmp::{self};
+const TEXT_BIT: i32 = 1;
+const LINK_BIT: i32 = 2;
+const SCORE_SHIFT: i32 = 2;
 
-    use image::{GenericImage, GenericImageView, ImageBuffer, Luma};
-    use imageproc::{
-        definitions::Image, region_labelling::Connectivity, union_find::DisjointSetForest,
-    };
+/// Masks and scores, split from the buffer read back in [`get_detections`]
+struct ScoreMaps {
+    text_score: GrayImage,
+    link_score: GrayImage,
+    text_map: FloatGrayImage,
+    link_map: FloatGrayImage,
+}
 
-    #[derive(Default)]
-    pub struct Stats {
-        pub left: Vec<u32>,
-        pub top: Vec<u32>,
-        pub right: Vec<u32>,
-        pub bottom: Vec<u32>,
-        pub area: Vec<u32>,
+impl ScoreMaps {
+    fn unpack(packed: &[i32], link_map: &[i32], width: u32, height: u32) -> Self {
+        let bits = |bit: i32| packed.iter().map(|v| (v & bit != 0) as u8).collect();
+        let scores = packed
+            .iter()
+            .map(|v| (v >> SCORE_SHIFT) as f32 / SCORE_SCALE)
+            .collect();
+        let link_map = link_map.iter().map(|v| *v as f32 / SCORE_SCALE).collect();
+        Self {
+            text_score: GrayImage::from_vec(width, height, bits(TEXT_BIT)).unwrap(),
+            link_score: GrayImage::from_vec(width, height, bits(LINK_BIT)).unwrap(),
+            text_map: FloatGrayImage::from_vec(width, height, scores).unwrap(),
+            link_map: FloatGrayImage::from_vec(width, height, link_map).unwrap(),
+        }
     }
 
-    pub struct ConnectedComponentsResult {
-        pub stats: Stats,
-        pub num_labels: u32,
-        pub labels: Image<Luma<u32>>,
+    /// Whether the pixel with linear index `i` is only part of a link
+    fn link_only(&self, i: usize) -> bool {
+        self.text_score.as_raw()[i] == 0 && self.link_score.as_raw()[i] == 1
     }
 
-    #[allow(clippy::needless_range_loop)]
-    pub fn connected_components_with_stats<I>(
-        image: &I,
-        conn: Connectivity,
-        background: I::Pixel,
-    ) -> ConnectedComponentsResult
-    where
-        I: GenericImage,
-        I::Pixel: Eq,
-    {
-        let (width, height) = image.dimensions();
-        let image_size = width as usize * height as usize;
-        if image_size >= 2usize.saturating_pow(32) {
-            panic!("Images with 2^32 or more pixels are not supported");
-        }
-
-        let mut out = ImageBuffer::new(width, height);
-
-        // TODO: add macro to abandon early if either dimension is zero
-        if width == 0 || height == 0 {
-            return ConnectedComponentsResult {
-                stats: Stats::default(),
-                num_labels: 0,
-                labels: out,
-            };
-        }
-
-        let mut forest = DisjointSetForest::new(image_size);
-        let mut adj_labels = [0u32; 4];
-        let mut next_label = 1;
-
-        for y in 0..height {
-            for x in 0..width {
-                let current = unsafe { image.unsafe_get_pixel(x, y) };
-                if current == background {
-                    continue;
-                }
-
-                let mut num_adj = 0;
+    /// Fraction of the pixels inside the convex quad `bbox` that are above `low_text`
+    fn coverage(&self, bbox: &[Point<f32>; 4]) -> f32 {
+        let (width, height) = self.text_score.dimensions();
+        let orientation = signed_area(bbox).signum();
+        let inside = |x: f32, y: f32| {
+            (0..4).all(|i| {
+                let (a, b) = (bbox[i], bbox[(i + 1) % 4]);
+                orientation * ((b.x - a.x) * (y - a.y) - (b.y - a.y) * (x - a.x)) >= 0.0
+            })
+        };
 
-                if x > 0 {
-                    // West
-                    let pixel = unsafe { image.unsafe_get_pixel(x - 1, y) };
-                    if pixel == current {
-                        let label = unsafe { out.unsafe_get_pixel(x - 1, y)[0] };
-                        adj_labels[num_adj] = label;
-                        num_adj += 1;
-                    }
+        let left = bbox.iter().map(|p| p.x).fold(f32::MAX, f32::min).max(0.0) as u32;
+        let top = bbox.iter().map(|p| p.y).fold(f32::MAX, f32::min).max(0.0) as u32;
+        let right = (bbox.iter().map(|p| p.x).fold(0.0, f32::max) as u32).min(width - 1);
+        let bottom = (bbox.iter().map(|p| p.y).fold(0.0, f32::max) as u32).min(height - 1);
+
+        let (mut total, mut covered) = (0, 0);
+        for y in top..=bottom {
+            for x in left..=right {
+                if inside(x as f32, y as f32) {
+                    total += 1;
+                    covered += self.text_score.get_pixel(x, y)[0] as u32;
                 }
+            }
+        }
+        covered as f32 / total.max(1) as f32
+    }
+}
 
-                if y > 0 {
-                    // North
-                    let pixel = unsafe { image.unsafe_get_pixel(x, y - 1) };
-                    if pixel == current {
-                        let label = unsafe { out.unsafe_get_pixel(x, y - 1)[0] };
-                        adj_labels[num_adj] = label;
-                        num_adj += 1;
-                    }
-
-                    if conn == Connectivity::Eight {
-                        if x > 0 {
-                            // North West
-                            let pixel = unsafe { image.unsafe_get_pixel(x - 1, y - 1) };
-                            if pixel == current {
-                                let label = unsafe { out.unsafe_get_pixel(x - 1, y - 1)[0] };
-                                adj_labels[num_adj] = label;
-                                num_adj += 1;
-                            }
-                        }
-                        if x < width - 1 {
-                            // North East
-                            let pixel = unsafe { image.unsafe_get_pixel(x + 1, y - 1) };
-                            if pixel == current {
-                                let label = unsafe { out.unsafe_get_pixel(x + 1, y - 1)[0] };
-                                adj_labels[num_adj] = label;
-                                num_adj += 1;
-                            }
-                        }
-                    }
-                }
+/// Score statistics of a segment found by [`seg_map`]
+struct Segment {
+    max_text: f32,
+    text_sum: f32,
+    text_count: u32,
+    link_sum: f32,
+    link_count: u32,
+}
 
-                if num_adj == 0 {
-                    unsafe {
-                        out.unsafe_put_pixel(x, y, Luma([next_label]));
-                    }
-                    next_label += 1;
-                } else {
-                    let mut min_label = u32::MAX;
-                    for n in 0..num_adj {
-                        min_label = cmp::min(min_label, adj_labels[n]);
-                    }
-                    unsafe {
-                        out.unsafe_put_pixel(x, y, Luma([min_label]));
-                    }
-                    for n in 0..num_adj {
-                        forest.union(min_label as usize, adj_labels[n] as usize);
-                    }
-                }
-            }
+impl Segment {
+    fn new() -> Self {
+        Self {
+            max_text: f32::MIN,
+            text_sum: 0.0,
+            text_count: 0,
+            link_sum: 0.0,
+            link_count: 0,
         }
+    }
 
-        // Make components start at 1
-        let mut output_labels = vec![0u32; image_size];
-        let mut count = 1;
-        let forest_count = forest.num_trees();
-        let mut stats = Stats {
-            left: vec![u32::MAX; forest_count],
-            top: vec![u32::MAX; forest_count],
-            right: vec![0u32; forest_count],
-            bottom: vec![0u32; forest_count],
-            area: vec![0u32; forest_count],
-        };
+    /// Add the pixel with linear index `i` to the statistics
+    fn add(&mut self, i: usize, maps: &ScoreMaps) {
+        let score = maps.text_map.as_raw()[i];
+        self.max_text = self.max_text.max(score);
+        if maps.text_score.as_raw()[i] == 1 {
+            self.text_sum += score;
+            self.text_count += 1;
+        }
+        if maps.link_score.as_raw()[i] == 1 {
+            self.link_sum += maps.link_map.as_raw()[i];
+            self.link_count += 1;
+        }
+    }
+}
 
-        unsafe {
-            for y in 0..height {
-                for x in 0..width {
-                    let label = {
-                        if image.unsafe_get_pixel(x, y) == background {
-                            continue;
-                        }
-                        out.unsafe_get_pixel(x, y)[0]
-                    };
-                    let root = forest.root(label as usize);
-                    let mut output_label = *output_labels.get_unchecked(root);
-                    if output_label < 1 {
-                        output_label = count;
-                        count += 1;
-                    }
-                    *output_labels.get_unchecked_mut(root) = output_label;
-                    out.unsafe_put_pixel(x, y, Luma([output_label]));
-                    let label = output_label as usize;
-                    let left = stats.left.get_unchecked_mut(label);
-                    let right = stats.right.get_unchecked_mut(label);
-                    let top = stats.top.get_unchecked_mut(label);
-                    let bottom = stats.bottom.get_unchecked_mut(label);
-                    *left = (*left).min(x);
-                    *top = (*top).min(y);
-                    *right = (*right).max(x);
-                    *bottom = (*bottom).max(y);
-                    *stats.area.get_unchecked_mut(label) += 1;
-                }
-            }
+/// Flood fill the component containing `seed`, leaving out pixels that are only part of a link.
+/// The component must lie entirely inside `roi`, and the output covers only `roi`. Also returns
+/// the score statistics of the component.
+fn seg_map(
+    seed: u32,
+    roi: Rect,
+    maps: &ScoreMaps,
+    connectivity: Connectivity,
+) -> (GrayImage, Segment) {
+    let image_width = maps.text_score.width() as usize;
+    let (left, top) = (roi.left() as usize, roi.top() as usize);
+    let (width, height) = (roi.width() as usize, roi.height() as usize);
+    let mut out = GrayImage::new(roi.width(), roi.height());
+    let mut visited = vec![false; width * height];
+    let mut segment = Segment::new();
+
+    let to_roi = |i: usize| (i % image_width - left, i / image_width - top);
+    let (x, y) = to_roi(seed as usize);
+    let mut stack = vec![(x, y)];
+    visited[y * width + x] = true;
+
+    let text = maps.text_score.as_raw();
+    let link = maps.link_score.as_raw();
+    let out_raw: &mut [u8] = &mut out;
+    while let Some((x, y)) = stack.pop() {
+        let i = (y + top) * image_width + x + left;
+        segment.add(i, maps);
+        if !maps.link_only(i) {
+            out_raw[y * width + x] = 255;
         }
 
-        ConnectedComponentsResult {
-            stats,
-            num_labels: count,
-            labels: out,
+        for (nx, ny) in neighbours((x, y), (width, height), connectivity) {
+            let n = (ny + top) * image_width + nx + left;
+            if !visited[ny * width + nx] && (text[n] != 0 || link[n] != 0) {
+                visited[ny * width + nx] = true;
+                stack.push((nx, ny));
+            }
         }
     }
+    (out, segment)
+}
+
+/// Offsets of the 4-connected neighbours, followed by the diagonal ones
+const NEIGHBOURS: [(isize, isize); 8] = [
+    (-1, 0),
+    (1, 0),
+    (0, -1),
+    (0, 1),
+    (-1, -1),
+    (1, -1),
+    (-1, 1),
+    (1, 1),
+];
+
+/// Neighbours of `(x, y)` that lie inside an image of the given size
+fn neighbours(
+    (x, y): (usize, usize),
+    (width, height): (usize, usize),
+    connectivity: Connectivity,
+) -> impl Iterator<Item = (usize, usize)> {
+    let count = match connectivity {
+        Connectivity::Four => 4,
+        Connectivity::Eight => 8,
+    };
+    NEIGHBOURS[..count].iter().filter_map(move |(dx, dy)| {
+        let (nx, ny) = (x.checked_add_signed(*dx)?, y.checked_add_signed(*dy)?);
+        (nx < width && ny < height).then_some((nx, ny))
+    })
 }
@@ -0,0 +1,397 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    f32::consts::{FRAC_PI_2, PI},
+};
+
+use burn::{prelude::Backend, tensor::Tensor};
+use imageproc::{geometry::min_area_rect, point::Point, region_labelling::Connectivity};
+use rayon::prelude::*;
+
+use super::{
+    align_square,
+    connected::{quantized_threshold, SCORE_SCALE},
+    mask::{word_labels, WordLabels},
+    neighbours, PostProcessConfig,
+};
+use crate::{geometry::distance, image_util::ImageTransform};
+
+/// A single character, in heatmap coordinates like the word boxes
+#[derive(Clone, Debug)]
+pub struct CharBox {
+    pub bbox: [Point<f32>; 4],
+    /// Id of the word containing the character, matching the keys returned by
+    /// [`post_process`](super::post_process) for the same inputs and config
+    pub word: u32,
+    /// Position of the highest region score
+    pub peak: Point<f32>,
+    /// Highest region score in the character
+    pub score: f32,
+    /// Position of the character along the affinity chain of its word, starting at 0
+    pub position: u32,
+}
+
+/// Direction in which the characters of a detection follow each other
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TextDirection {
+    /// Also used for detections without a clear direction
+    #[default]
+    Horizontal,
+    /// Characters run from top to bottom
+    Vertical,
+    /// Characters run along the given angle in radians, clockwise from the x axis and in
+    /// `(-pi, pi]`
+    Rotated(f32),
+}
+
+impl TextDirection {
+    /// Angle of the reading direction in radians, clockwise from the x axis
+    pub fn angle(&self) -> f32 {
+        match self {
+            TextDirection::Horizontal => 0.0,
+            TextDirection::Vertical => FRAC_PI_2,
+            TextDirection::Rotated(angle) => *angle,
+        }
+    }
+
+    /// Whether the text runs closer to vertical than horizontal, in either sense
+    pub fn is_vertical(&self) -> bool {
+        let (sin, cos) = self.angle().sin_cos();
+        sin.abs() > cos.abs()
+    }
+}
+
+/// Estimate the text direction of each of `detections` from its characters, as returned by
+/// [`get_char_boxes`]. The direction runs from the first to the last character peak along the
+/// affinity chain of the word, see [`CharBox::position`], and is mapped through `transform` like
+/// the boxes are by [`adjust_coordinates`](super::adjust_coordinates). It is snapped to horizontal
+/// or vertical when within `tolerance` radians. Words with fewer than two characters, including
+/// words where no character peak was found, get the default [`TextDirection::Horizontal`], so
+/// every detection has a direction.
+///
+/// The affinity chain has no preferred end, so characters are assumed to run towards increasing x
+/// in the heatmap, or increasing y for vertical text. Only a rotation in `transform` turns the
+/// direction around, so text that is upside down in the network input reads as upright.
+pub fn text_directions<T>(
+    detections: &HashMap<u32, T>,
+    chars: &[CharBox],
+    transform: &ImageTransform,
+    tolerance: f32,
+) -> HashMap<u32, TextDirection> {
+    let mut words: HashMap<u32, Vec<&CharBox>> =
+        detections.keys().map(|id| (*id, Vec::new())).collect();
+    for c in chars {
+        if let Some(chars) = words.get_mut(&c.word) {
+            chars.push(c);
+        }
+    }
+
+    words
+        .into_iter()
+        .map(|(word, chars)| {
+            let (Some(first), Some(last)) = (
+                chars.iter().min_by_key(|c| c.position),
+                chars.iter().max_by_key(|c| c.position),
+            ) else {
+                return (word, TextDirection::default());
+            };
+            let v = transform.to_image(last.peak) - transform.to_image(first.peak);
+            if v.x == 0.0 && v.y == 0.0 {
+                return (word, TextDirection::default());
+            }
+
+            let angle = v.y.atan2(v.x);
+            let direction = if angle.abs() <= tolerance {
+                TextDirection::Horizontal
+            } else if (angle - FRAC_PI_2).abs() <= tolerance {
+                TextDirection::Vertical
+            } else if angle <= -PI + tolerance {
+                TextDirection::Rotated(PI)
+            } else {
+                TextDirection::Rotated(angle)
+            };
+            (word, direction)
+        })
+        .collect()
+}
+
+/// Extract character boxes from the region score. Pixels of the region score above `low_text` are
+/// split into characters with a watershed, seeded at local maxima of at least `text_threshold`.
+/// Words are segmented the same way as in [`post_process`](super::post_process) with the same
+/// `config`, and characters never cross word boundaries. Characters are ordered by word, then by
+/// their position along the affinity chain of the word.
+pub fn get_char_boxes<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    config: &PostProcessConfig,
+) -> Vec<CharBox> {
+    let thresholds = config.resolve_thresholds(&text_map, &link_map);
+    let Some(WordLabels {
+        labels,
+        maps,
+        stats,
+        num_labels,
+    }) = word_labels(text_map, link_map, &thresholds, config)
+    else {
+        return Vec::new();
+    };
+
+    let quantized = |threshold: f64| (quantized_threshold(threshold) * SCORE_SCALE) as i32;
+    let scores: Vec<_> = maps
+        .text_map
+        .iter()
+        .map(|s| (s * SCORE_SCALE) as i32)
+        .collect();
+    let links: Vec<_> = (0..labels.len()).map(|i| maps.link_only(i)).collect();
+    let words = WordMaps {
+        labels: &labels,
+        scores: &scores,
+        links: &links,
+        width: maps.text_map.width() as usize,
+        low_text: quantized(thresholds.low_text),
+        text_threshold: quantized(thresholds.text_threshold),
+        square_tolerance: config.square_tolerance,
+    };
+
+    // Words dropped by the post-processing have no labelled pixels, and so no characters
+    (1..num_labels)
+        .into_par_iter()
+        .filter(|k| stats.area[*k as usize] >= config.min_area)
+        .flat_map_iter(|k| {
+            let k = k as usize;
+            let roi = Roi {
+                left: stats.left[k] as usize,
+                top: stats.top[k] as usize,
+                width: (stats.right[k] - stats.left[k]) as usize + 1,
+                height: (stats.bottom[k] - stats.top[k]) as usize + 1,
+            };
+            words.split_characters(k as u32, roi)
+        })
+        .collect()
+}
+
+struct Roi {
+    left: usize,
+    top: usize,
+    width: usize,
+    height: usize,
+}
+
+/// Word labels and quantized region score read back from the device
+struct WordMaps<'a> {
+    labels: &'a [u32],
+    scores: &'a [i32],
+    /// Pixels that are only part of a link, which belong to no word in `labels`
+    links: &'a [bool],
+    width: usize,
+    low_text: i32,
+    text_threshold: i32,
+    square_tolerance: f32,
+}
+
+const UNLABELLED: u32 = 0;
+const OUTSIDE: u32 = u32::MAX;
+
+impl WordMaps<'_> {
+    /// Split word `word`, which lies entirely inside `roi`, into characters
+    fn split_characters(&self, word: u32, roi: Roi) -> Vec<CharBox> {
+        let Roi { width, height, .. } = roi;
+        let index = |x: usize, y: usize| (y + roi.top) * self.width + x + roi.left;
+        let score = |x: usize, y: usize| self.scores[index(x, y)];
+
+        // Character pixels of this word, in ROI coordinates
+        let mut markers = vec![OUTSIDE; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let i = index(x, y);
+                if self.labels[i] == word && self.scores[i] >= self.low_text {
+                    markers[y * width + x] = UNLABELLED;
+                }
+            }
+        }
+
+        let neighbours =
+            |x: usize, y: usize, conn: Connectivity| neighbours((x, y), (width, height), conn);
+
+        // Seeds are local maxima of the region score. Plateaus form a single seed.
+        let is_peak = |x: usize, y: usize| {
+            let s = score(x, y);
+            s >= self.text_threshold
+                && neighbours(x, y, Connectivity::Eight)
+                    .filter(|(nx, ny)| markers[ny * width + nx] != OUTSIDE)
+                    .all(|(nx, ny)| score(nx, ny) <= s)
+        };
+        let mut peaks = vec![false; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                peaks[y * width + x] = markers[y * width + x] != OUTSIDE && is_peak(x, y);
+            }
+        }
+
+        let mut queue = BinaryHeap::new();
+        let mut num_markers = 0;
+        for start in 0..width * height {
+            if !peaks[start] || markers[start] != UNLABELLED {
+                continue;
+            }
+            num_markers += 1;
+            let marker = num_markers as u32;
+            let mut stack = vec![start];
+            markers[start] = marker;
+            while let Some(i) = stack.pop() {
+                let (x, y) = (i % width, i / width);
+                queue.push((score(x, y), Reverse(i)));
+                for (nx, ny) in neighbours(x, y, Connectivity::Eight) {
+                    let n = ny * width + nx;
+                    if peaks[n] && markers[n] == UNLABELLED {
+                        markers[n] = marker;
+                        stack.push(n);
+                    }
+                }
+            }
+        }
+
+        // Flood from the seeds in order of decreasing score, so characters meet at the valleys
+        // between them
+        while let Some((_, Reverse(i))) = queue.pop() {
+            let (x, y) = (i % width, i / width);
+            let marker = markers[i];
+            for (nx, ny) in neighbours(x, y, Connectivity::Four) {
+                let n = ny * width + nx;
+                if markers[n] == UNLABELLED {
+                    markers[n] = marker;
+                    queue.push((score(nx, ny), Reverse(n)));
+                }
+            }
+        }
+
+        // Link pixels join the characters on either side of them, so characters are adjacent
+        // along the affinity chain where their pixels touch, directly or through a link
+        let mut regions = markers.clone();
+        for y in 0..height {
+            for x in 0..width {
+                if regions[y * width + x] == OUTSIDE && self.links[index(x, y)] {
+                    regions[y * width + x] = UNLABELLED;
+                }
+            }
+        }
+        let is_char = |marker: u32| marker != UNLABELLED && marker != OUTSIDE;
+        let mut queue: VecDeque<_> = (0..width * height)
+            .filter(|i| is_char(regions[*i]))
+            .collect();
+        while let Some(i) = queue.pop_front() {
+            for (nx, ny) in neighbours(i % width, i / width, Connectivity::Four) {
+                let n = ny * width + nx;
+                if regions[n] == UNLABELLED {
+                    regions[n] = regions[i];
+                    queue.push_back(n);
+                }
+            }
+        }
+        let mut adjacent = vec![Vec::new(); num_markers];
+        for (i, a) in regions.iter().enumerate() {
+            if !is_char(*a) {
+                continue;
+            }
+            for (nx, ny) in neighbours(i % width, i / width, Connectivity::Eight) {
+                let b = regions[ny * width + nx];
+                if !is_char(b) || b == *a {
+                    continue;
+                }
+                let (a, b) = (*a as usize - 1, b as usize - 1);
+                if !adjacent[a].contains(&b) {
+                    adjacent[a].push(b);
+                }
+            }
+        }
+
+        let mut points = vec![Vec::new(); num_markers];
+        let mut maxima = vec![(i32::MIN, Point::new(0, 0)); num_markers];
+        for (i, marker) in markers.iter().enumerate() {
+            if !is_char(*marker) {
+                continue;
+            }
+            let (x, y) = (i % width, i / width);
+            let m = *marker as usize - 1;
+            let point = Point::new((x + roi.left) as i32, (y + roi.top) as i32);
+            points[m].push(point);
+            if score(x, y) > maxima[m].0 {
+                maxima[m] = (score(x, y), point);
+            }
+        }
+
+        let as_float = |p: Point<i32>| Point::new(p.x as f32, p.y as f32);
+        let peaks: Vec<_> = maxima.iter().map(|(_, peak)| as_float(*peak)).collect();
+        chain_order(&adjacent, &peaks)
+            .into_iter()
+            .enumerate()
+            .map(|(position, m)| CharBox {
+                bbox: align_square(
+                    min_area_rect(&points[m]).map(as_float),
+                    self.square_tolerance,
+                ),
+                word,
+                peak: peaks[m],
+                score: maxima[m].0 as f32 / SCORE_SCALE,
+                position: position as u32,
+            })
+            .collect()
+    }
+}
+
+/// Order characters along their affinity chain, given the characters `adjacent` to each and their
+/// `peaks`. The chain runs between the two characters furthest apart, found with two breadth first
+/// searches, preferring the peaks furthest apart on ties and when no characters are adjacent.
+/// It starts at the end that makes it run towards increasing x, or increasing y if vertical.
+/// Characters that can't be reached along the chain come last, by their distance to its start.
+fn chain_order(adjacent: &[Vec<usize>], peaks: &[Point<f32>]) -> Vec<usize> {
+    let num_chars = peaks.len();
+    // Hops along the chain and distance between the peaks, with unreachable characters counting
+    // as zero hops away
+    let distances = |start: usize| {
+        let mut hops = vec![None; num_chars];
+        hops[start] = Some(0);
+        let mut queue = VecDeque::from([start]);
+        while let Some(i) = queue.pop_front() {
+            for &n in &adjacent[i] {
+                if hops[n].is_none() {
+                    hops[n] = hops[i].map(|h| h + 1);
+                    queue.push_back(n);
+                }
+            }
+        }
+        hops.into_iter()
+            .zip(peaks)
+            .map(|(hops, peak)| (hops, distance(peaks[start], *peak)))
+            .collect::<Vec<_>>()
+    };
+    let furthest = |start: usize| {
+        let distances = distances(start);
+        let key = |i: usize| (distances[i].0.unwrap_or(0), distances[i].1);
+        (0..num_chars)
+            .max_by(|a, b| {
+                let ((ha, da), (hb, db)) = (key(*a), key(*b));
+                ha.cmp(&hb).then(da.total_cmp(&db))
+            })
+            .unwrap_or(start)
+    };
+    if num_chars == 0 {
+        return Vec::new();
+    }
+
+    let mut start = furthest(0);
+    let end = furthest(start);
+    let v = peaks[end] - peaks[start];
+    if v.x < 0.0 || (v.x == 0.0 && v.y < 0.0) {
+        start = end;
+    }
+
+    let distances = distances(start);
+    let mut order: Vec<_> = (0..num_chars).collect();
+    order.sort_by(|a, b| {
+        let ((ha, da), (hb, db)) = (distances[*a], distances[*b]);
+        let hops = |h: Option<u32>| h.unwrap_or(u32::MAX);
+        hops(ha).cmp(&hops(hb)).then(da.total_cmp(&db))
+    });
+    order
+}
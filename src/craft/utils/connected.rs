@@ -0,0 +1,1091 @@
+use std::{
+    cmp::{self},
+    collections::HashMap,
+    ops::Range,
+};
+
+use burn::{
+    prelude::Backend,
+    tensor::{Bool, Element, Int, Numeric, Tensor},
+};
+use image::{GenericImage, GenericImageView, GrayImage, ImageBuffer, Luma};
+use imageproc::{
+    definitions::Image, region_labelling::Connectivity, union_find::DisjointSetForest,
+};
+use rayon::prelude::*;
+
+use super::{thresholds::Thresholds, Labelling, ScoreMaps, LINK_BIT, SCORE_SHIFT, TEXT_BIT};
+
+#[derive(Default)]
+pub struct Stats {
+    pub left: Vec<u32>,
+    pub top: Vec<u32>,
+    pub right: Vec<u32>,
+    pub bottom: Vec<u32>,
+    pub area: Vec<u32>,
+}
+
+pub struct ConnectedComponentsResult {
+    pub stats: Stats,
+    pub num_labels: u32,
+    pub labels: Image<Luma<u32>>,
+}
+
+pub fn connected_components_with_stats<I>(
+    image: &I,
+    conn: Connectivity,
+    background: I::Pixel,
+) -> ConnectedComponentsResult
+where
+    I: GenericImage,
+    I::Pixel: Eq,
+{
+    let (width, height) = image.dimensions();
+    let image_size = width as usize * height as usize;
+    if image_size >= 2usize.saturating_pow(32) {
+        panic!("Images with 2^32 or more pixels are not supported");
+    }
+
+    let mut out = ImageBuffer::new(width, height);
+
+    // TODO: add macro to abandon early if either dimension is zero
+    if width == 0 || height == 0 {
+        return ConnectedComponentsResult {
+            stats: Stats::default(),
+            num_labels: 0,
+            labels: out,
+        };
+    }
+
+    let mut forest = label_rows(image, 0..height, conn, background, &mut out);
+
+    // Make components start at 1
+    let mut output_labels = vec![0u32; image_size + 1];
+    let mut count = 1;
+    let forest_count = forest.num_trees();
+    let mut stats = Stats {
+        left: vec![u32::MAX; forest_count],
+        top: vec![u32::MAX; forest_count],
+        right: vec![0u32; forest_count],
+        bottom: vec![0u32; forest_count],
+        area: vec![0u32; forest_count],
+    };
+
+    unsafe {
+        for y in 0..height {
+            for x in 0..width {
+                let label = {
+                    if image.unsafe_get_pixel(x, y) == background {
+                        continue;
+                    }
+                    out.unsafe_get_pixel(x, y)[0]
+                };
+                let root = forest.root(label as usize);
+                let mut output_label = *output_labels.get_unchecked(root);
+                if output_label < 1 {
+                    output_label = count;
+                    count += 1;
+                }
+                *output_labels.get_unchecked_mut(root) = output_label;
+                out.unsafe_put_pixel(x, y, Luma([output_label]));
+                let label = output_label as usize;
+                let left = stats.left.get_unchecked_mut(label);
+                let right = stats.right.get_unchecked_mut(label);
+                let top = stats.top.get_unchecked_mut(label);
+                let bottom = stats.bottom.get_unchecked_mut(label);
+                *left = (*left).min(x);
+                *top = (*top).min(y);
+                *right = (*right).max(x);
+                *bottom = (*bottom).max(y);
+                *stats.area.get_unchecked_mut(label) += 1;
+            }
+        }
+    }
+
+    ConnectedComponentsResult {
+        stats,
+        num_labels: count,
+        labels: out,
+    }
+}
+
+/// Minimum number of rows per strip in [`par_connected_components_with_stats`]
+const MIN_STRIP_ROWS: u32 = 16;
+
+/// Parallel version of [`connected_components_with_stats`]. The image is split into horizontal
+/// strips that are labelled concurrently, after which labels are merged along the strip boundaries
+/// and the per-strip stats are reduced. Produces the same labels and stats as the sequential
+/// version.
+pub fn par_connected_components_with_stats<I>(
+    image: &I,
+    conn: Connectivity,
+    background: I::Pixel,
+) -> ConnectedComponentsResult
+where
+    I: GenericImageView + Sync,
+    I::Pixel: Eq + Sync,
+{
+    let (width, height) = image.dimensions();
+    let image_size = width as usize * height as usize;
+    if image_size >= 2usize.saturating_pow(32) {
+        panic!("Images with 2^32 or more pixels are not supported");
+    }
+
+    let mut out: Image<Luma<u32>> = ImageBuffer::new(width, height);
+
+    if width == 0 || height == 0 {
+        return ConnectedComponentsResult {
+            stats: Stats::default(),
+            num_labels: 0,
+            labels: out,
+        };
+    }
+
+    let strip_rows = height
+        .div_ceil(rayon::current_num_threads() as u32)
+        .max(MIN_STRIP_ROWS);
+    let strip_len = strip_rows as usize * width as usize;
+
+    // Label each strip independently and collect stats for each strip local root. Labels in `out`
+    // are local roots after this.
+    let strips: Vec<StripStats> = out
+        .par_chunks_mut(strip_len)
+        .enumerate()
+        .map(|(i, labels)| {
+            let top = i as u32 * strip_rows;
+            let rows = top..top + (labels.len() / width as usize) as u32;
+            let mut forest = label_rows(image, rows.clone(), conn, background, labels);
+
+            let mut strip = StripStats::new(top, labels.len() + 1);
+            for (i, label) in labels.iter_mut().enumerate() {
+                if *label == 0 {
+                    continue;
+                }
+                *label = forest.root(*label as usize) as u32;
+                let (x, y) = (
+                    (i % width as usize) as u32,
+                    top + (i / width as usize) as u32,
+                );
+                strip.add(*label as usize, x, y, width);
+            }
+            strip
+        })
+        .collect();
+
+    // Merge labels across strip boundaries. Global labels are strip local labels offset by the
+    // index of the first pixel of the strip, so they are unique.
+    let mut forest = DisjointSetForest::new(image_size + 1);
+    let global = |y: u32, label: u32| {
+        (y / strip_rows * strip_rows) as usize * width as usize + label as usize
+    };
+    for top in (strip_rows..height).step_by(strip_rows as usize) {
+        let above = top - 1;
+        for x in 0..width {
+            let current = unsafe { image.unsafe_get_pixel(x, top) };
+            if current == background {
+                continue;
+            }
+            let label = global(top, out.get_pixel(x, top)[0]);
+
+            let neighbours = match conn {
+                Connectivity::Four => x..x + 1,
+                Connectivity::Eight => x.saturating_sub(1)..(x + 2).min(width),
+            };
+            for nx in neighbours {
+                let pixel = unsafe { image.unsafe_get_pixel(nx, above) };
+                if pixel == current {
+                    forest.union(label, global(above, out.get_pixel(nx, above)[0]));
+                }
+            }
+        }
+    }
+
+    // Reduce the strip stats by global root and number components by their first pixel, which
+    // matches the order the sequential version assigns labels in.
+    let mut components: HashMap<usize, Component> = HashMap::new();
+    for strip in &strips {
+        for (label, component) in strip.components() {
+            let root = forest.root(global(strip.top, label as u32));
+            components
+                .entry(root)
+                .and_modify(|c| c.merge(&component))
+                .or_insert(component);
+        }
+    }
+    let mut roots: Vec<_> = components
+        .iter()
+        .map(|(root, c)| (c.first, *root))
+        .collect();
+    roots.sort_unstable();
+    let output_labels: HashMap<usize, u32> = roots
+        .iter()
+        .enumerate()
+        .map(|(i, (_, root))| (*root, i as u32 + 1))
+        .collect();
+
+    let luts: Vec<Vec<u32>> = strips
+        .iter()
+        .map(|strip| {
+            let mut lut = vec![0; strip.first.len()];
+            for (label, _) in strip.components() {
+                let root = forest.root(global(strip.top, label as u32));
+                lut[label] = output_labels[&root];
+            }
+            lut
+        })
+        .collect();
+    out.par_chunks_mut(strip_len)
+        .zip(luts)
+        .for_each(|(labels, lut)| {
+            for label in labels.iter_mut().filter(|label| **label != 0) {
+                *label = lut[*label as usize];
+            }
+        });
+
+    let num_labels = roots.len() + 1;
+    let mut stats = Stats {
+        left: vec![u32::MAX; num_labels],
+        top: vec![u32::MAX; num_labels],
+        right: vec![0u32; num_labels],
+        bottom: vec![0u32; num_labels],
+        area: vec![0u32; num_labels],
+    };
+    for (root, component) in components {
+        let label = output_labels[&root] as usize;
+        stats.left[label] = component.left;
+        stats.top[label] = component.top;
+        stats.right[label] = component.right;
+        stats.bottom[label] = component.bottom;
+        stats.area[label] = component.area;
+    }
+
+    ConnectedComponentsResult {
+        stats,
+        num_labels: num_labels as u32,
+        labels: out,
+    }
+}
+
+/// Stats accumulated for the components of a single strip, indexed by strip local root
+struct StripStats {
+    top: u32,
+    stats: Stats,
+    /// Linear index of the first pixel of each component in raster order
+    first: Vec<u32>,
+}
+
+#[derive(Clone, Copy)]
+struct Component {
+    left: u32,
+    top: u32,
+    right: u32,
+    bottom: u32,
+    area: u32,
+    /// Linear index of the first pixel in raster order
+    first: u32,
+}
+
+impl StripStats {
+    fn new(top: u32, num_labels: usize) -> Self {
+        Self {
+            top,
+            stats: Stats {
+                left: vec![u32::MAX; num_labels],
+                top: vec![u32::MAX; num_labels],
+                right: vec![0; num_labels],
+                bottom: vec![0; num_labels],
+                area: vec![0; num_labels],
+            },
+            first: vec![u32::MAX; num_labels],
+        }
+    }
+
+    fn add(&mut self, label: usize, x: u32, y: u32, width: u32) {
+        let stats = &mut self.stats;
+        stats.left[label] = stats.left[label].min(x);
+        stats.top[label] = stats.top[label].min(y);
+        stats.right[label] = stats.right[label].max(x);
+        stats.bottom[label] = stats.bottom[label].max(y);
+        stats.area[label] += 1;
+        self.first[label] = self.first[label].min(y * width + x);
+    }
+
+    fn components(&self) -> impl Iterator<Item = (usize, Component)> + '_ {
+        (0..self.first.len())
+            .filter(|label| self.stats.area[*label] > 0)
+            .map(|label| {
+                let component = Component {
+                    left: self.stats.left[label],
+                    top: self.stats.top[label],
+                    right: self.stats.right[label],
+                    bottom: self.stats.bottom[label],
+                    area: self.stats.area[label],
+                    first: self.first[label],
+                };
+                (label, component)
+            })
+    }
+}
+
+impl Component {
+    fn merge(&mut self, other: &Component) {
+        self.left = self.left.min(other.left);
+        self.top = self.top.min(other.top);
+        self.right = self.right.max(other.right);
+        self.bottom = self.bottom.max(other.bottom);
+        self.area += other.area;
+        self.first = self.first.min(other.first);
+    }
+}
+
+/// First pass of the two-pass labelling, restricted to `rows`. Provisional labels starting at 1
+/// are written to `out`, which covers exactly those rows, and label equivalences are recorded in
+/// the returned forest. Pixels outside of `rows` are ignored.
+#[allow(clippy::needless_range_loop)]
+fn label_rows<I>(
+    image: &I,
+    rows: Range<u32>,
+    conn: Connectivity,
+    background: I::Pixel,
+    out: &mut [u32],
+) -> DisjointSetForest
+where
+    I: GenericImageView,
+    I::Pixel: Eq,
+{
+    let (width, _) = image.dimensions();
+    let index = |x: u32, y: u32| (y - rows.start) as usize * width as usize + x as usize;
+    let mut forest = DisjointSetForest::new(out.len() + 1);
+    let mut adj_labels = [0u32; 4];
+    let mut next_label = 1;
+
+    for y in rows.clone() {
+        for x in 0..width {
+            let current = unsafe { image.unsafe_get_pixel(x, y) };
+            if current == background {
+                continue;
+            }
+
+            let mut num_adj = 0;
+
+            if x > 0 {
+                // West
+                let pixel = unsafe { image.unsafe_get_pixel(x - 1, y) };
+                if pixel == current {
+                    let label = out[index(x - 1, y)];
+                    adj_labels[num_adj] = label;
+                    num_adj += 1;
+                }
+            }
+
+            if y > rows.start {
+                // North
+                let pixel = unsafe { image.unsafe_get_pixel(x, y - 1) };
+                if pixel == current {
+                    let label = out[index(x, y - 1)];
+                    adj_labels[num_adj] = label;
+                    num_adj += 1;
+                }
+
+                if conn == Connectivity::Eight {
+                    if x > 0 {
+                        // North West
+                        let pixel = unsafe { image.unsafe_get_pixel(x - 1, y - 1) };
+                        if pixel == current {
+                            let label = out[index(x - 1, y - 1)];
+                            adj_labels[num_adj] = label;
+                            num_adj += 1;
+                        }
+                    }
+                    if x < width - 1 {
+                        // North East
+                        let pixel = unsafe { image.unsafe_get_pixel(x + 1, y - 1) };
+                        if pixel == current {
+                            let label = out[index(x + 1, y - 1)];
+                            adj_labels[num_adj] = label;
+                            num_adj += 1;
+                        }
+                    }
+                }
+            }
+
+            if num_adj == 0 {
+                out[index(x, y)] = next_label;
+                next_label += 1;
+            } else {
+                let mut min_label = u32::MAX;
+                for n in 0..num_adj {
+                    min_label = cmp::min(min_label, adj_labels[n]);
+                }
+                out[index(x, y)] = min_label;
+                for n in 0..num_adj {
+                    forest.union(min_label as usize, adj_labels[n] as usize);
+                }
+            }
+        }
+    }
+
+    forest
+}
+
+/// Scale used to quantize scores in `[0, 1]` to integers on the device
+pub const SCORE_SCALE: f32 = 16384.0;
+
+/// Components labelled by [`connected_components_device`]. Nothing has been read back yet.
+pub struct DeviceComponents<B: Backend> {
+    /// Compact per-component stats with one row per [`TableRow`] and one column per component
+    pub table: Tensor<B, 2, Int>,
+    pub num_labels: u32,
+    pub labels: Tensor<B, 2, Int>,
+}
+
+/// Rows of [`DeviceComponents::table`]. Minimums are stored flipped so they can be reduced with a
+/// max during propagation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableRow {
+    /// Linear index of the last pixel of the component, plus one
+    Label,
+    Right,
+    LeftFlipped,
+    Bottom,
+    TopFlipped,
+    /// Maximum score, quantized with [`SCORE_SCALE`]
+    MaxScore,
+    Area,
+}
+
+pub const TABLE_ROWS: usize = 7;
+/// Rows that are propagated between pixels, the score is only propagated if one is given
+const PROPAGATED: usize = 5;
+
+/// Host side stats of components labelled on the device
+pub struct DeviceComponentsResult {
+    pub stats: Stats,
+    /// Highest score of each component, clamped to `[0, 1]` and quantized
+    pub max_score: Vec<f32>,
+    /// Linear index of one pixel of each component, usable as a flood fill seed
+    pub seeds: Vec<u32>,
+    pub num_labels: u32,
+}
+
+/// Iterations between convergence checks. Each check is a device sync.
+const CHECK_INTERVAL: usize = 4;
+
+/// Label the connected components of `mask` on the device.
+///
+/// Every foreground pixel starts out with its own linear index as label, and labels are then
+/// propagated by taking the maximum over the neighbourhood defined by `conn`, followed by a pointer
+/// jump to the label of the pixel each label points at. Bounding box and maximum `score` are
+/// propagated alongside the labels, and the stats are compacted on the device. The only transfers
+/// are a two element readback every [`CHECK_INTERVAL`] iterations, holding the number of changed
+/// values and the number of components, and whatever the caller reads from the result. Labels are
+/// numbered in raster order of the last pixel of each component, starting at 1.
+pub fn connected_components_device<B: Backend>(
+    mask: Tensor<B, 2, Bool>,
+    score: Option<Tensor<B, 2>>,
+    conn: Connectivity,
+) -> DeviceComponents<B> {
+    let [height, width] = mask.dims();
+    let device = mask.device();
+    let size = height * width;
+
+    let empty = || DeviceComponents {
+        table: Tensor::zeros([TABLE_ROWS, 0], &device),
+        num_labels: 0,
+        labels: Tensor::zeros([height, width], &device),
+    };
+    if size == 0 {
+        return empty();
+    }
+
+    let foreground = mask.int().reshape([1, height, width]);
+    let index = Tensor::<B, 1, Int>::arange(1..size as i64 + 1, &device);
+    let xs = Tensor::<B, 1, Int>::arange(0..width as i64, &device)
+        .reshape([1, 1, width])
+        .expand([1, height, width]);
+    let ys = Tensor::<B, 1, Int>::arange(0..height as i64, &device)
+        .reshape([1, height, 1])
+        .expand([1, height, width]);
+
+    let mut channels = vec![
+        index.clone().reshape([1, height, width]),
+        xs.clone(),
+        xs.neg() + (width as i64 - 1),
+        ys.clone(),
+        ys.neg() + (height as i64 - 1),
+    ];
+    channels.extend(score.map(|score| quantize(score).reshape([1, height, width])));
+    let num_channels = channels.len();
+    let mut state = Tensor::cat(channels, 0) * foreground.clone();
+
+    let mut iteration = 0;
+    let num_roots = loop {
+        let parent = (state.clone().narrow(0, 0, 1).reshape([size]) - 1).clamp_min(0);
+
+        let next = neighbour_max(state.clone(), conn);
+        let jumped = next
+            .clone()
+            .reshape([num_channels, size])
+            .select(1, parent)
+            .reshape([num_channels, height, width]);
+        let next = max(next, jumped) * foreground.clone();
+
+        iteration += 1;
+        let converged = if iteration % CHECK_INTERVAL == 0 {
+            // The number of roots is read along with the check, so it needs no sync of its own
+            let changed = next.clone().not_equal(state.clone()).int().sum();
+            let roots = root_mask(next.clone(), index.clone()).sum();
+            let check = Tensor::cat(vec![changed, roots], 0).into_data();
+            let check = check.convert::<i64>().to_vec::<i64>().unwrap();
+            (check[0] == 0).then_some(check[1] as usize)
+        } else {
+            None
+        };
+
+        state = next;
+        if let Some(num_roots) = converged {
+            break num_roots;
+        }
+    };
+    if num_roots == 0 {
+        return empty();
+    }
+
+    // Each component is now labelled with the index of its last pixel, which is the root. Roots
+    // are numbered densely in raster order by a prefix sum, so the stats can be compacted on the
+    // device without reading the labels back.
+    let labels = state.clone().narrow(0, 0, 1).reshape([size]);
+    let is_root = root_mask(state.clone(), index.clone());
+    let dense = inclusive_scan(is_root.clone()) * is_root.clone();
+    let roots = Tensor::<B, 1, Int>::zeros([num_roots + 1], &device)
+        .scatter(0, dense.clone(), index * is_root)
+        .narrow(0, 1, num_roots)
+        - 1;
+    let lut = Tensor::cat(vec![Tensor::zeros([1], &device), dense], 0);
+    let labels = lut.select(0, labels);
+
+    let area = Tensor::<B, 1, Int>::zeros([num_roots + 1], &device)
+        .scatter(0, labels.clone(), Tensor::ones([size], &device))
+        .narrow(0, 1, num_roots)
+        .reshape([1, num_roots]);
+    let propagated = state.reshape([num_channels, size]).select(1, roots);
+    let max_score = if num_channels > PROPAGATED {
+        propagated.clone().narrow(0, PROPAGATED, 1)
+    } else {
+        Tensor::zeros([1, num_roots], &device)
+    };
+    let table = Tensor::cat(
+        vec![propagated.narrow(0, 0, PROPAGATED), max_score, area],
+        0,
+    );
+    let labels = labels.reshape([height, width]);
+
+    DeviceComponents {
+        table,
+        num_labels: num_roots as u32 + 1,
+        labels,
+    }
+}
+
+/// Quantize a score map with [`SCORE_SCALE`] after clamping it to `[0, 1]`
+pub fn quantize<B: Backend, const D: usize>(score: Tensor<B, D>) -> Tensor<B, D, Int> {
+    (score.clamp(0.0, 1.0) * SCORE_SCALE).int()
+}
+
+impl<B: Backend> DeviceComponents<B> {
+    /// Read back the stats table
+    pub fn read(self) -> DeviceComponentsResult {
+        let [height, width] = self.labels.dims();
+        let table = self.table.into_data().convert::<i32>().to_vec::<i32>();
+        decode_table(&table.unwrap(), width, height)
+    }
+}
+
+/// Decode a stats table read back from [`DeviceComponents::table`] for an image of the given size
+pub fn decode_table(table: &[i32], width: usize, height: usize) -> DeviceComponentsResult {
+    let num_roots = table.len() / TABLE_ROWS;
+    let row = |row: TableRow| {
+        let row = row as usize;
+        &table[row * num_roots..(row + 1) * num_roots]
+    };
+
+    let with_background = |background: u32, values: Vec<u32>| {
+        let mut out = Vec::with_capacity(num_roots + 1);
+        out.push(background);
+        out.extend(values);
+        out
+    };
+    let plain = |r: TableRow| row(r).iter().map(|v| *v as u32).collect();
+    let flipped = |r: TableRow, size: usize| {
+        row(r)
+            .iter()
+            .map(|v| (size - 1) as u32 - *v as u32)
+            .collect()
+    };
+
+    let stats = Stats {
+        left: with_background(u32::MAX, flipped(TableRow::LeftFlipped, width)),
+        top: with_background(u32::MAX, flipped(TableRow::TopFlipped, height)),
+        right: with_background(0, plain(TableRow::Right)),
+        bottom: with_background(0, plain(TableRow::Bottom)),
+        area: with_background(0, plain(TableRow::Area)),
+    };
+    let max_score = row(TableRow::MaxScore)
+        .iter()
+        .map(|v| *v as f32 / SCORE_SCALE);
+    let seeds = row(TableRow::Label).iter().map(|v| *v as u32 - 1).collect();
+
+    DeviceComponentsResult {
+        stats,
+        max_score: [0.0].into_iter().chain(max_score).collect(),
+        seeds: with_background(0, seeds),
+        num_labels: num_roots as u32 + 1,
+    }
+}
+
+/// Threshold to compare quantized scores against. Scores are quantized by truncation, so the
+/// threshold is truncated the same way.
+pub fn quantized_threshold(threshold: f64) -> f32 {
+    (threshold as f32 * SCORE_SCALE).floor() / SCORE_SCALE
+}
+
+/// Components of the thresholded score maps, read back by [`label_and_read_back`]
+pub(super) struct LabelledMaps {
+    /// Component of each pixel, 0 for the background. Empty unless requested.
+    pub labels: Vec<u32>,
+    pub maps: ScoreMaps,
+    pub components: DeviceComponentsResult,
+}
+
+/// Label the components of the pixels with a region score of at least `seed_threshold` or a link
+/// score of at least `link_threshold`, where `labelling` says. The masks above `low_text` and
+/// `link_threshold`, both quantized score maps and, when labelling on the device, the component
+/// stats and, with `read_labels`, the labels are packed into a single buffer, so apart from the
+/// convergence checks of [`connected_components_device`] there is only one transfer. `None` if
+/// there are no components.
+pub(super) fn label_and_read_back<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    thresholds: &Thresholds,
+    seed_threshold: f64,
+    conn: Connectivity,
+    labelling: Labelling,
+    read_labels: bool,
+) -> Option<LabelledMaps> {
+    let [_, height, width, _] = text_map.shape().dims::<4>();
+    let size = height * width;
+    let text_map = text_map.reshape([height, width]);
+    let link_map = link_map.reshape([height, width]);
+
+    let text_score = text_map
+        .clone()
+        .greater_equal_elem(thresholds.low_text)
+        .int();
+    let link_score = link_map
+        .clone()
+        .greater_equal_elem(thresholds.link_threshold)
+        .int();
+    let components = match labelling {
+        Labelling::Device => {
+            let seeds =
+                text_map.clone().greater_equal_elem(seed_threshold).int() + link_score.clone();
+            let components =
+                connected_components_device(seeds.greater_elem(0), Some(text_map.clone()), conn);
+            if components.num_labels <= 1 {
+                return None;
+            }
+            Some(components)
+        }
+        Labelling::Cpu => None,
+    };
+
+    let packed =
+        quantize(text_map) * (1 << SCORE_SHIFT) + text_score * TEXT_BIT + link_score * LINK_BIT;
+    let mut data = vec![packed.reshape([size]), quantize(link_map).reshape([size])];
+    let num_components = components.as_ref().map_or(0, |c| c.num_labels as usize - 1);
+    if let Some(components) = components {
+        data.push(components.table.reshape([TABLE_ROWS * num_components]));
+        if read_labels {
+            data.push(components.labels.reshape([size]));
+        }
+    }
+    let data = Tensor::cat(data, 0);
+    let data = data.into_data().convert::<i32>().to_vec::<i32>().unwrap();
+    let (packed, rest) = data.split_at(size);
+    let (link_map, rest) = rest.split_at(size);
+    let maps = ScoreMaps::unpack(packed, link_map, width as u32, height as u32);
+
+    if labelling == Labelling::Cpu {
+        return label_on_cpu(maps, seed_threshold, conn, read_labels);
+    }
+    let (table, labels) = rest.split_at(TABLE_ROWS * num_components);
+    Some(LabelledMaps {
+        labels: labels.iter().map(|l| *l as u32).collect(),
+        maps,
+        components: decode_table(table, width, height),
+    })
+}
+
+/// Maps with fewer pixels are labelled sequentially by [`label_on_cpu`], since the strips would
+/// be too small to make up for merging them
+const PARALLEL_MIN_PIXELS: usize = 1 << 18;
+
+/// CPU version of the labelling in [`label_and_read_back`], on maps that were already read back.
+/// Seeds are the last pixel of each component, like on the device, but components are numbered
+/// in raster order of their first pixel.
+fn label_on_cpu(
+    maps: ScoreMaps,
+    seed_threshold: f64,
+    conn: Connectivity,
+    read_labels: bool,
+) -> Option<LabelledMaps> {
+    let (width, height) = maps.text_map.dimensions();
+    let seed_threshold = quantized_threshold(seed_threshold);
+    let mask = GrayImage::from_fn(width, height, |x, y| {
+        let seed = maps.text_map.get_pixel(x, y)[0] >= seed_threshold
+            || maps.link_score.get_pixel(x, y)[0] == 1;
+        Luma([seed as u8])
+    });
+    let ConnectedComponentsResult {
+        stats,
+        num_labels,
+        labels,
+    } = if (width * height) as usize >= PARALLEL_MIN_PIXELS {
+        par_connected_components_with_stats(&mask, conn, Luma([0]))
+    } else {
+        connected_components_with_stats(&mask, conn, Luma([0]))
+    };
+    if num_labels <= 1 {
+        return None;
+    }
+
+    let mut max_score = vec![0.0f32; num_labels as usize];
+    let mut seeds = vec![0; num_labels as usize];
+    for (i, (label, score)) in labels.iter().zip(maps.text_map.iter()).enumerate() {
+        let k = *label as usize;
+        if k != 0 {
+            max_score[k] = max_score[k].max(*score);
+            seeds[k] = i as u32;
+        }
+    }
+
+    Some(LabelledMaps {
+        labels: if read_labels {
+            labels.into_raw()
+        } else {
+            Vec::new()
+        },
+        maps,
+        components: DeviceComponentsResult {
+            stats,
+            max_score,
+            seeds,
+            num_labels,
+        },
+    })
+}
+
+/// 1 for the pixels of `state` whose label is their own linear index, plus one
+fn root_mask<B: Backend>(state: Tensor<B, 3, Int>, index: Tensor<B, 1, Int>) -> Tensor<B, 1, Int> {
+    let size = index.dims()[0];
+    state.narrow(0, 0, 1).reshape([size]).equal(index).int()
+}
+
+/// Inclusive prefix sum of `x`, in `log2(len)` shifted additions
+fn inclusive_scan<B: Backend>(x: Tensor<B, 1, Int>) -> Tensor<B, 1, Int> {
+    let size = x.dims()[0];
+    let mut out = x.reshape([1, 1, size]);
+    let mut offset = 1;
+    while offset < size {
+        out = out.clone() + shift_dim(out, 2, offset as isize);
+        offset *= 2;
+    }
+    out.reshape([size])
+}
+
+fn max<B: Backend, K: Numeric<B>, const D: usize>(
+    a: Tensor<B, D, K>,
+    b: Tensor<B, D, K>,
+) -> Tensor<B, D, K>
+where
+    K::Elem: Element,
+{
+    let lower = a.clone().lower(b.clone());
+    a.mask_where(lower, b)
+}
+
+/// Maximum over each pixel and its neighbours. Pixels outside the image count as zero.
+fn neighbour_max<B: Backend, K: Numeric<B>>(
+    x: Tensor<B, 3, K>,
+    conn: Connectivity,
+) -> Tensor<B, 3, K>
+where
+    K::Elem: Element,
+{
+    const FOUR: [(isize, isize); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+    const EIGHT: [(isize, isize); 8] = [
+        (1, 0),
+        (-1, 0),
+        (0, 1),
+        (0, -1),
+        (1, 1),
+        (-1, 1),
+        (1, -1),
+        (-1, -1),
+    ];
+
+    let offsets: &[(isize, isize)] = match conn {
+        Connectivity::Four => &FOUR,
+        Connectivity::Eight => &EIGHT,
+    };
+    offsets.iter().fold(x.clone(), |out, &(dx, dy)| {
+        max(out, shift(x.clone(), dx, dy))
+    })
+}
+
+/// Shift the last two dimensions of `x` by `dx` and `dy`, filling with zeros.
+fn shift<B: Backend, K: Numeric<B>>(x: Tensor<B, 3, K>, dx: isize, dy: isize) -> Tensor<B, 3, K>
+where
+    K::Elem: Element,
+{
+    shift_dim(shift_dim(x, 2, dx), 1, dy)
+}
+
+fn shift_dim<B: Backend, K: Numeric<B>>(
+    x: Tensor<B, 3, K>,
+    dim: usize,
+    offset: isize,
+) -> Tensor<B, 3, K>
+where
+    K::Elem: Element,
+{
+    let size = x.dims()[dim];
+    let amount = offset.unsigned_abs();
+    if amount == 0 {
+        return x;
+    }
+    if amount >= size {
+        return x.zeros_like();
+    }
+
+    let mut pad_shape = x.dims();
+    pad_shape[dim] = amount;
+    let zeros = Tensor::zeros(pad_shape, &x.device());
+    if offset > 0 {
+        Tensor::cat(vec![zeros, x.narrow(dim, 0, size - amount)], dim)
+    } else {
+        Tensor::cat(vec![x.narrow(dim, amount, size - amount), zeros], dim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::{backend::NdArray, tensor::TensorData};
+    use image::GrayImage;
+
+    use super::*;
+
+    /// Xorshift, so the masks are the same on every run
+    fn rng() -> impl FnMut() -> u64 {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        }
+    }
+
+    fn assert_same(image: &GrayImage, conn: Connectivity) {
+        let sequential = connected_components_with_stats(image, conn, Luma([0]));
+        // Strips are sized by the thread count, so use enough threads to get several
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(8)
+            .build()
+            .unwrap();
+        let parallel = pool.install(|| par_connected_components_with_stats(image, conn, Luma([0])));
+        let size = image.dimensions();
+        assert_eq!(
+            sequential.num_labels, parallel.num_labels,
+            "{size:?} {conn:?}"
+        );
+        assert_eq!(sequential.labels, parallel.labels, "{size:?} {conn:?}");
+
+        let num_labels = sequential.num_labels as usize;
+        let (a, b) = (&sequential.stats, &parallel.stats);
+        for k in 1..num_labels {
+            let bounds = |s: &Stats| (s.left[k], s.top[k], s.right[k], s.bottom[k], s.area[k]);
+            assert_eq!(bounds(a), bounds(b), "{size:?} {conn:?} label {k}");
+        }
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let mut next = rng();
+
+        // Single rows and columns, and sizes spanning several strips
+        let mut sizes = vec![
+            (1, 1),
+            (1, 100),
+            (100, 1),
+            (1, 2 * MIN_STRIP_ROWS + 1),
+            (257, 1),
+        ];
+        for _ in 0..40 {
+            sizes.push((
+                1 + next() as u32 % 60,
+                1 + next() as u32 % (5 * MIN_STRIP_ROWS),
+            ));
+        }
+
+        for (width, height) in sizes {
+            let density = next() % 100;
+            let image = GrayImage::from_fn(width, height, |_, _| {
+                Luma([((next() % 100) < density) as u8])
+            });
+            for conn in [Connectivity::Four, Connectivity::Eight] {
+                assert_same(&image, conn);
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_merges_across_strips() {
+        // A snake winding down through every strip, and diagonal steps that only join with
+        // 8-connectivity
+        let (width, height) = (20, 6 * MIN_STRIP_ROWS);
+        let snake = GrayImage::from_fn(width, height, |x, y| {
+            let turn = (y / 4) % 2 == 0;
+            let row = y % 4 == 0;
+            Luma([(row || (turn && x == width - 1) || (!turn && x == 0)) as u8])
+        });
+        let diagonal = GrayImage::from_fn(width, height, |x, y| Luma([(x == y % width) as u8]));
+
+        for image in [snake, diagonal] {
+            for conn in [Connectivity::Four, Connectivity::Eight] {
+                assert_same(&image, conn);
+            }
+        }
+    }
+
+    /// Compare the device labelling of `image` with [`connected_components_with_stats`], up to the
+    /// numbering of the components. Scores are multiples of `1 / SCORE_SCALE`, so quantizing them
+    /// is exact.
+    fn assert_device_same(image: &GrayImage, conn: Connectivity) {
+        let reference = connected_components_with_stats(image, conn, Luma([0]));
+        let (width, height) = image.dimensions();
+        let (w, h) = (width as usize, height as usize);
+        let score = |i: usize| (i * 7919 % (SCORE_SCALE as usize + 1)) as i32;
+
+        let device = Default::default();
+        let mask: Vec<_> = image.pixels().map(|p| p[0] as i32).collect();
+        let mask = Tensor::<NdArray, 2, Int>::from_data(TensorData::new(mask, [h, w]), &device);
+        let scores: Vec<_> = (0..w * h).map(|i| score(i) as f32 / SCORE_SCALE).collect();
+        let scores = Tensor::<NdArray, 2>::from_data(TensorData::new(scores, [h, w]), &device);
+
+        let components = connected_components_device(mask.greater_elem(0), Some(scores), conn);
+        let labels = components.labels.clone().into_data();
+        let labels = labels.convert::<i32>().to_vec::<i32>().unwrap();
+        let result = components.read();
+
+        let size = image.dimensions();
+        assert_eq!(reference.num_labels, result.num_labels, "{size:?} {conn:?}");
+        let num_labels = reference.num_labels as usize;
+
+        // Each reference label maps to exactly one device label, and the counts match, so the
+        // mapping is a bijection
+        let mut renumber = vec![None; num_labels];
+        let mut max_score = vec![0; num_labels];
+        for (i, (pixel, &label)) in reference.labels.pixels().zip(&labels).enumerate() {
+            let k = pixel[0] as usize;
+            assert_eq!(
+                *renumber[k].get_or_insert(label),
+                label,
+                "{size:?} {conn:?} pixel {i}"
+            );
+            if k > 0 {
+                max_score[k] = max_score[k].max(score(i));
+            }
+        }
+        assert_eq!(renumber[0], Some(0), "{size:?} {conn:?}");
+
+        let (a, b) = (&reference.stats, &result.stats);
+        let bounds =
+            |s: &Stats, k: usize| (s.left[k], s.top[k], s.right[k], s.bottom[k], s.area[k]);
+        for (k, label) in renumber.into_iter().enumerate().skip(1) {
+            let d = label.unwrap() as usize;
+            assert_eq!(bounds(a, k), bounds(b, d), "{size:?} {conn:?} label {k}");
+            assert_eq!(
+                max_score[k] as f32 / SCORE_SCALE,
+                result.max_score[d],
+                "{size:?} {conn:?} label {k}"
+            );
+            assert_eq!(
+                labels[result.seeds[d] as usize] as usize, d,
+                "{size:?} {conn:?} label {k}"
+            );
+        }
+    }
+
+    #[test]
+    fn device_matches_sequential() {
+        let mut next = rng();
+        let mut sizes = vec![(1, 1), (1, 40), (40, 1)];
+        for _ in 0..20 {
+            sizes.push((1 + next() as u32 % 32, 1 + next() as u32 % 32));
+        }
+
+        for (width, height) in sizes {
+            let density = next() % 100;
+            let image = GrayImage::from_fn(width, height, |_, _| {
+                Luma([((next() % 100) < density) as u8])
+            });
+            for conn in [Connectivity::Four, Connectivity::Eight] {
+                assert_device_same(&image, conn);
+            }
+        }
+    }
+
+    #[test]
+    fn device_converges_on_long_paths() {
+        // A spiral and a serpentine, which need many propagation rounds since their labels have
+        // to travel the whole path, and a diagonal that only joins with 8-connectivity
+        let n = 31;
+        let mut spiral = GrayImage::new(n, n);
+        let (mut x, mut y) = (0i32, 0i32);
+        let mut length = n as i32 - 1;
+        for (turn, (dx, dy)) in [(1, 0), (0, 1), (-1, 0), (0, -1)]
+            .into_iter()
+            .cycle()
+            .enumerate()
+        {
+            if length <= 0 {
+                break;
+            }
+            for _ in 0..length {
+                spiral.put_pixel(x as u32, y as u32, Luma([1]));
+                x += dx;
+                y += dy;
+            }
+            // The first three sides span the whole square, then every two sides shrink by two
+            if turn >= 2 && turn % 2 == 0 {
+                length -= 2;
+            }
+        }
+        spiral.put_pixel(x as u32, y as u32, Luma([1]));
+
+        let (width, height) = (24, 33);
+        let serpentine = GrayImage::from_fn(width, height, |x, y| {
+            let turn = (y / 2) % 2 == 0;
+            let row = y % 2 == 0;
+            Luma([(row || (turn && x == width - 1) || (!turn && x == 0)) as u8])
+        });
+        let diagonal = GrayImage::from_fn(width, height, |x, y| Luma([(x == y % width) as u8]));
+
+        for image in [spiral, serpentine, diagonal] {
+            for conn in [Connectivity::Four, Connectivity::Eight] {
+                assert_device_same(&image, conn);
+            }
+        }
+    }
+}
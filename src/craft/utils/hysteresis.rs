@@ -0,0 +1,197 @@
+use std::collections::{HashMap, VecDeque};
+
+use burn::{prelude::Backend, tensor::Tensor};
+use image::GrayImage;
+use imageproc::{rect::Rect, region_labelling::Connectivity};
+use rayon::prelude::*;
+
+use super::{
+    connected::{label_and_read_back, quantized_threshold, LabelledMaps, Stats},
+    detection, dilation_roi, neighbours, post_process,
+    thresholds::Thresholds,
+    Detection, PostProcessConfig, ScoreCalibration, ScoreMaps, Segment, Segmentation,
+};
+
+/// Same as [`get_detections`](super::get_detections), but segments words with hysteresis instead
+/// of a single `low_text` threshold.
+///
+/// Seeds are the connected pixels above `text_threshold` or `link_threshold`, so characters are
+/// only joined into a word by the link map. Seeds without a pixel above `text_threshold` are
+/// dropped. Each seed then grows into the neighbouring pixels above `low_text`, with every pixel
+/// going to the seed it is closest to. Words that touch through weak pixels therefore stay
+/// separate.
+pub fn get_detections_hysteresis<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    text_threshold: f64,
+    link_threshold: f64,
+    low_text: f64,
+    calibration: &ScoreCalibration,
+) -> HashMap<u32, Detection> {
+    let config = PostProcessConfig::new()
+        .with_thresholds(Thresholds {
+            text_threshold,
+            link_threshold,
+            low_text,
+        })
+        .with_segmentation(Segmentation::Hysteresis)
+        .with_calibration(calibration.clone());
+    post_process(text_map, link_map, &config).1
+}
+
+pub(super) fn segment<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    thresholds: &Thresholds,
+    config: &PostProcessConfig,
+) -> HashMap<u32, Detection> {
+    let Some(Regions {
+        labels,
+        maps,
+        stats,
+        num_labels,
+    }) = regions(text_map, link_map, thresholds, config)
+    else {
+        return HashMap::new();
+    };
+    let (width, height) = maps.text_score.dimensions();
+    let (width, height) = (width as usize, height as usize);
+
+    (1..num_labels)
+        .into_par_iter()
+        .filter_map(|k| {
+            if stats.area[k as usize] < config.min_area {
+                return None;
+            }
+            let (roi, radius) = dilation_roi(&stats, k as usize, (width, height), config);
+            let (seg_map, segment) = region_map(k, roi, &labels, &maps);
+            detection(seg_map, roi, radius, &segment, &maps, config).map(|d| (k, d))
+        })
+        .collect()
+}
+
+/// Regions grown by hysteresis, along with the maps they were grown on
+pub(super) struct Regions {
+    /// Region of each pixel, 0 for the background
+    pub labels: Vec<u32>,
+    pub maps: ScoreMaps,
+    pub stats: Stats,
+    pub num_labels: u32,
+}
+
+/// Grow the regions with hysteresis, see [`get_detections_hysteresis`]. Regions smaller than
+/// `config.min_area` are not removed.
+pub(super) fn regions<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    thresholds: &Thresholds,
+    config: &PostProcessConfig,
+) -> Option<Regions> {
+    let [_, height, width, _] = text_map.shape().dims::<4>();
+    let LabelledMaps {
+        labels,
+        maps,
+        components,
+    } = label_and_read_back(
+        text_map,
+        link_map,
+        thresholds,
+        thresholds.text_threshold,
+        config.connectivity.into(),
+        config.labelling,
+        true,
+    )?;
+
+    let text_threshold = quantized_threshold(thresholds.text_threshold);
+    let mut labels: Vec<u32> = labels
+        .into_iter()
+        .map(|l| {
+            if components.max_score[l as usize] >= text_threshold {
+                l
+            } else {
+                0
+            }
+        })
+        .collect();
+    grow(
+        &mut labels,
+        maps.text_score.as_raw(),
+        (width, height),
+        config.connectivity.into(),
+    );
+    let num_labels = components.num_labels;
+    let stats = region_stats(&labels, num_labels as usize, width, height);
+    Some(Regions {
+        labels,
+        maps,
+        stats,
+        num_labels,
+    })
+}
+
+/// Grow the labelled regions into the unlabelled `weak` pixels breadth first, so each pixel goes to
+/// the region it is closest to along connected paths
+pub(super) fn grow(
+    labels: &mut [u32],
+    weak: &[u8],
+    (width, height): (usize, usize),
+    conn: Connectivity,
+) {
+    let mut queue: VecDeque<usize> = (0..labels.len()).filter(|i| labels[*i] != 0).collect();
+    while let Some(i) = queue.pop_front() {
+        for (x, y) in neighbours((i % width, i / width), (width, height), conn) {
+            let n = y * width + x;
+            if labels[n] == 0 && weak[n] != 0 {
+                labels[n] = labels[i];
+                queue.push_back(n);
+            }
+        }
+    }
+}
+
+/// Bounding box and area of each region, with the background at index 0
+fn region_stats(labels: &[u32], num_labels: usize, width: usize, height: usize) -> Stats {
+    let mut stats = Stats {
+        left: vec![u32::MAX; num_labels],
+        top: vec![u32::MAX; num_labels],
+        right: vec![0; num_labels],
+        bottom: vec![0; num_labels],
+        area: vec![0; num_labels],
+    };
+    for y in 0..height {
+        for x in 0..width {
+            let k = labels[y * width + x] as usize;
+            if k == 0 {
+                continue;
+            }
+            stats.left[k] = stats.left[k].min(x as u32);
+            stats.top[k] = stats.top[k].min(y as u32);
+            stats.right[k] = stats.right[k].max(x as u32);
+            stats.bottom[k] = stats.bottom[k].max(y as u32);
+            stats.area[k] += 1;
+        }
+    }
+    stats
+}
+
+/// Mask of region `k` inside `roi`, leaving out pixels that are only part of a link, and its
+/// score statistics
+fn region_map(k: u32, roi: Rect, labels: &[u32], maps: &ScoreMaps) -> (GrayImage, Segment) {
+    let image_width = maps.text_score.width() as usize;
+    let mut out = GrayImage::new(roi.width(), roi.height());
+    let mut segment = Segment::new();
+    for y in 0..roi.height() {
+        for x in 0..roi.width() {
+            let i =
+                (y as usize + roi.top() as usize) * image_width + x as usize + roi.left() as usize;
+            if labels[i] != k {
+                continue;
+            }
+            segment.add(i, maps);
+            if !maps.link_only(i) {
+                out.put_pixel(x, y, [255].into());
+            }
+        }
+    }
+    (out, segment)
+}
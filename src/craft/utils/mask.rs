@@ -0,0 +1,202 @@
+use burn::{config::Config, prelude::Backend, tensor::Tensor};
+use image::{GrayImage, Luma};
+use imageproc::{
+    definitions::Image, morphology::dilate_mut, point::Point, region_labelling::Connectivity,
+};
+
+use super::{
+    connected::{label_and_read_back, quantized_threshold, LabelledMaps, Stats},
+    hysteresis::{grow, regions, Regions},
+    thresholds::Thresholds,
+    DilationNorm, PostProcessConfig, ScoreMaps, Segmentation,
+};
+use crate::image_util::ImageTransform;
+
+#[derive(Config, Debug)]
+pub struct MaskConfig {
+    /// Dilate the mask by this many pixels of the original image
+    #[config(default = 0)]
+    dilation: u8,
+    #[config(default = "DilationNorm::L1")]
+    dilation_norm: DilationNorm,
+    /// Also label each pixel with the detection it belongs to
+    #[config(default = false)]
+    instance_labels: bool,
+}
+
+pub struct TextMask {
+    /// 255 on text and 0 elsewhere, at the size of the original image
+    pub mask: GrayImage,
+    /// Detection each pixel belongs to, with the ids used by
+    /// [`post_process`](super::post_process), and 0 for the background. Only set with
+    /// `instance_labels`.
+    pub labels: Option<Image<Luma<u32>>>,
+    pub thresholds: Thresholds,
+}
+
+/// Pixel mask of the text found by [`post_process`](super::post_process) with the same `config`,
+/// at the size of the original image that `transform` maps back to. Contains the segments the
+/// boxes are fitted to before their dilation, so pixels that are only part of a link are left out.
+/// Heatmap pixels are upsampled with nearest neighbour sampling.
+pub fn text_mask<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    transform: &ImageTransform,
+    config: &PostProcessConfig,
+    mask_config: &MaskConfig,
+) -> TextMask {
+    let [_, height, width, _] = text_map.shape().dims::<4>();
+    let thresholds = config.resolve_thresholds(&text_map, &link_map);
+    let heatmap_labels = word_labels(text_map, link_map, &thresholds, config)
+        .map_or_else(|| vec![0; height * width], |words| words.labels);
+
+    let mut labels = upsample(&heatmap_labels, (width, height), transform);
+    let mut mask = GrayImage::from_fn(labels.width(), labels.height(), |x, y| {
+        Luma([(labels.get_pixel(x, y)[0] != 0) as u8 * 255])
+    });
+
+    if mask_config.dilation > 0 {
+        dilate_mut(
+            &mut mask,
+            mask_config.dilation_norm.into(),
+            mask_config.dilation,
+        );
+        if mask_config.instance_labels {
+            // Dilated pixels go to the closest detection
+            let size = (labels.width() as usize, labels.height() as usize);
+            grow(&mut labels, mask.as_raw(), size, Connectivity::Eight);
+        }
+    }
+
+    TextMask {
+        mask,
+        labels: mask_config.instance_labels.then_some(labels),
+        thresholds,
+    }
+}
+
+/// Words found by [`post_process`](super::post_process), with the maps they were found on
+pub(super) struct WordLabels {
+    /// Word of each pixel, with the ids used by `post_process`, and 0 for the background and the
+    /// pixels that are only part of a link
+    pub labels: Vec<u32>,
+    pub maps: ScoreMaps,
+    /// Bounds of each word, including the pixels that are only part of a link
+    pub stats: Stats,
+    pub num_labels: u32,
+}
+
+/// Label the pixels of the words [`post_process`](super::post_process) finds with the same
+/// `config`, already thresholded with `thresholds`. `None` if there are no components.
+pub(super) fn word_labels<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    thresholds: &Thresholds,
+    config: &PostProcessConfig,
+) -> Option<WordLabels> {
+    match config.segmentation {
+        Segmentation::Threshold => threshold_labels(text_map, link_map, thresholds, config),
+        Segmentation::Hysteresis => hysteresis_labels(text_map, link_map, thresholds, config),
+    }
+}
+
+/// Labels of the components kept by [`segment`](super::segment)
+fn threshold_labels<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    thresholds: &Thresholds,
+    config: &PostProcessConfig,
+) -> Option<WordLabels> {
+    let LabelledMaps {
+        labels,
+        maps,
+        components,
+    } = label_and_read_back(
+        text_map,
+        link_map,
+        thresholds,
+        thresholds.low_text,
+        config.connectivity.into(),
+        config.labelling,
+        true,
+    )?;
+
+    let text_threshold = quantized_threshold(thresholds.text_threshold);
+    let labels = labels
+        .iter()
+        .enumerate()
+        .map(|(i, l)| {
+            let k = *l as usize;
+            let kept = components.stats.area[k] >= config.min_area
+                && components.max_score[k] >= text_threshold;
+            if *l != 0 && kept && !maps.link_only(i) {
+                *l
+            } else {
+                0
+            }
+        })
+        .collect();
+    Some(WordLabels {
+        labels,
+        maps,
+        stats: components.stats,
+        num_labels: components.num_labels,
+    })
+}
+
+/// Labels of the regions kept by [`hysteresis::segment`](super::hysteresis::segment)
+fn hysteresis_labels<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    thresholds: &Thresholds,
+    config: &PostProcessConfig,
+) -> Option<WordLabels> {
+    let Regions {
+        labels,
+        maps,
+        stats,
+        num_labels,
+    } = regions(text_map, link_map, thresholds, config)?;
+
+    let labels = labels
+        .iter()
+        .enumerate()
+        .map(|(i, l)| {
+            let kept = stats.area[*l as usize] >= config.min_area && !maps.link_only(i);
+            if *l != 0 && kept {
+                *l
+            } else {
+                0
+            }
+        })
+        .collect();
+    Some(WordLabels {
+        labels,
+        maps,
+        stats,
+        num_labels,
+    })
+}
+
+/// Sample heatmap labels at every pixel of the original image
+fn upsample(
+    labels: &[u32],
+    (width, height): (usize, usize),
+    transform: &ImageTransform,
+) -> Image<Luma<u32>> {
+    let (image_width, image_height) = transform.image_size;
+    let crop = transform.crop;
+    Image::from_par_fn(image_width, image_height, |x, y| {
+        let (x, y) = (x as i32, y as i32);
+        let in_crop =
+            x >= crop.left() && x <= crop.right() && y >= crop.top() && y <= crop.bottom();
+        let p = transform.to_heatmap(Point::new(x as f32, y as f32));
+        let (hx, hy) = (p.x.round(), p.y.round());
+        let in_heatmap = hx >= 0.0 && hy >= 0.0 && hx < width as f32 && hy < height as f32;
+        if in_crop && in_heatmap {
+            Luma([labels[hy as usize * width + hx as usize]])
+        } else {
+            Luma([0])
+        }
+    })
+}
@@ -0,0 +1,198 @@
+use burn::{config::Config, prelude::Backend, tensor::Tensor};
+
+/// Thresholds used by [`get_det_boxes`](super::get_det_boxes)
+#[derive(Config, Debug, Copy, PartialEq)]
+pub struct Thresholds {
+    #[config(default = 0.7)]
+    pub text_threshold: f64,
+    #[config(default = 0.4)]
+    pub link_threshold: f64,
+    #[config(default = 0.4)]
+    pub low_text: f64,
+}
+
+/// How [`adaptive_thresholds`] derives the thresholds from the score distribution
+#[derive(Config, Debug, Copy, PartialEq)]
+pub enum ThresholdMethod {
+    /// Otsu's method on the region score gives `low_text`, and again on the region scores above
+    /// it gives `text_threshold`. Otsu on the link score gives `link_threshold`.
+    Otsu,
+    /// Percentiles of the scores above a noise floor of 0.1
+    Percentile {
+        text_threshold: f64,
+        link_threshold: f64,
+        low_text: f64,
+    },
+    /// Fractions of the highest score, for a high and a low threshold as used by hysteresis.
+    /// `text_threshold` uses `high`, the others `low`.
+    Hysteresis { high: f64, low: f64 },
+}
+
+#[derive(Config, Debug)]
+pub struct AdaptiveThresholdConfig {
+    #[config(default = "ThresholdMethod::Otsu")]
+    method: ThresholdMethod,
+    /// Lower bounds for the derived thresholds
+    #[config(default = "MIN_THRESHOLDS")]
+    min: Thresholds,
+    /// Upper bounds for the derived thresholds
+    #[config(default = "MAX_THRESHOLDS")]
+    max: Thresholds,
+    /// Thresholds used for maps without any text
+    #[config(default = "Thresholds::new()")]
+    fallback: Thresholds,
+}
+
+const MIN_THRESHOLDS: Thresholds = Thresholds {
+    text_threshold: 0.3,
+    link_threshold: 0.2,
+    low_text: 0.15,
+};
+const MAX_THRESHOLDS: Thresholds = Thresholds {
+    text_threshold: 0.9,
+    link_threshold: 0.6,
+    low_text: 0.6,
+};
+
+const BINS: usize = 256;
+/// Scores below this are considered background by [`ThresholdMethod::Percentile`]
+const NOISE_FLOOR: f64 = 0.1;
+
+/// Derive thresholds for one image from the distribution of its region and link scores. The
+/// results are clamped to the configured bounds, and `low_text` is kept at or below
+/// `text_threshold`. Maps where no score reaches the noise floor get the fallback thresholds.
+/// Returns the thresholds that were chosen.
+pub fn adaptive_thresholds<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    config: &AdaptiveThresholdConfig,
+) -> Thresholds {
+    // Compute both histograms on the device and read them back together
+    let histograms = Tensor::cat(vec![histogram(text_map), histogram(link_map)], 0);
+    let histograms = histograms
+        .into_data()
+        .convert::<i64>()
+        .to_vec::<i64>()
+        .unwrap();
+    let (text, link) = histograms.split_at(BINS);
+
+    let floor = bin(NOISE_FLOOR);
+    if text[floor..].iter().all(|count| *count == 0) {
+        return config.fallback;
+    }
+
+    let thresholds = match config.method {
+        ThresholdMethod::Otsu => {
+            let low_text = otsu(text, 0);
+            Thresholds {
+                text_threshold: otsu(text, bin(low_text)),
+                link_threshold: otsu(link, 0),
+                low_text,
+            }
+        }
+        ThresholdMethod::Percentile {
+            text_threshold,
+            link_threshold,
+            low_text,
+        } => Thresholds {
+            text_threshold: percentile(text, floor, text_threshold),
+            link_threshold: percentile(link, floor, link_threshold),
+            low_text: percentile(text, floor, low_text),
+        },
+        ThresholdMethod::Hysteresis { high, low } => Thresholds {
+            text_threshold: high * max_value(text),
+            link_threshold: low * max_value(link),
+            low_text: low * max_value(text),
+        },
+    };
+
+    let clamp = |value: f64, min: f64, max: f64| value.clamp(min, max.max(min));
+    let text_threshold = clamp(
+        thresholds.text_threshold,
+        config.min.text_threshold,
+        config.max.text_threshold,
+    );
+    Thresholds {
+        text_threshold,
+        link_threshold: clamp(
+            thresholds.link_threshold,
+            config.min.link_threshold,
+            config.max.link_threshold,
+        ),
+        low_text: clamp(
+            thresholds.low_text,
+            config.min.low_text,
+            config.max.low_text,
+        )
+        .min(text_threshold),
+    }
+}
+
+/// Histogram of scores in `[0, 1]` with `BINS` bins
+fn histogram<B: Backend>(map: Tensor<B, 4>) -> Tensor<B, 1, burn::tensor::Int> {
+    let size = map.shape().num_elements();
+    let device = map.device();
+    let bins = (map.reshape([size]).clamp(0.0, 1.0) * (BINS - 1) as f64)
+        .round()
+        .int();
+    Tensor::zeros([BINS], &device).scatter(0, bins, Tensor::ones([size], &device))
+}
+
+fn bin(value: f64) -> usize {
+    ((value * (BINS - 1) as f64).round() as usize).min(BINS - 1)
+}
+
+fn value(bin: usize) -> f64 {
+    bin as f64 / (BINS - 1) as f64
+}
+
+/// Otsu's threshold of the histogram bins from `start` on
+fn otsu(histogram: &[i64], start: usize) -> f64 {
+    let bins = &histogram[start..];
+    let total: f64 = bins.iter().sum::<i64>() as f64;
+    let sum: f64 = bins
+        .iter()
+        .enumerate()
+        .map(|(i, count)| i as f64 * *count as f64)
+        .sum();
+
+    let (mut best, mut best_variance) = (0, f64::MIN);
+    let (mut weight, mut partial) = (0.0, 0.0);
+    for (i, count) in bins.iter().enumerate() {
+        weight += *count as f64;
+        partial += i as f64 * *count as f64;
+        let rest = total - weight;
+        if weight == 0.0 || rest == 0.0 {
+            continue;
+        }
+        let mean_below = partial / weight;
+        let mean_above = (sum - partial) / rest;
+        let variance = weight * rest * (mean_below - mean_above).powi(2);
+        if variance > best_variance {
+            best_variance = variance;
+            best = i;
+        }
+    }
+    // Scores strictly above the best split are foreground
+    value(start + best + 1)
+}
+
+/// Score at quantile `q` of the histogram bins from `start` on
+fn percentile(histogram: &[i64], start: usize, q: f64) -> f64 {
+    let bins = &histogram[start..];
+    let total: i64 = bins.iter().sum();
+    let target = (q.clamp(0.0, 1.0) * total as f64).ceil() as i64;
+    let mut cumulative = 0;
+    for (i, count) in bins.iter().enumerate() {
+        cumulative += count;
+        if cumulative >= target.max(1) {
+            return value(start + i);
+        }
+    }
+    value(BINS - 1)
+}
+
+fn max_value(histogram: &[i64]) -> f64 {
+    let last = histogram.iter().rposition(|count| *count > 0).unwrap_or(0);
+    value(last)
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use burn::config::Config;
+use image::Pixel;
+use imageproc::{
+    definitions::{Clamp, Image},
+    geometric_transformations::{warp_into, warp_into_with, Interpolation, Projection},
+    point::Point,
+};
+use rayon::prelude::*;
+
+use crate::{
+    geometry::{distance, signed_area},
+    utils::characters::TextDirection,
+};
+
+#[derive(Config, Debug)]
+pub struct CropConfig {
+    /// Padding added around each box, relative to the box height
+    #[config(default = 0.0)]
+    padding: f32,
+    /// Resize each crop to this height, keeping the aspect ratio
+    #[config(default = "None")]
+    height: Option<u32>,
+    /// Rotate boxes that are taller than they are wide so the long side ends up horizontal. The
+    /// text is assumed to run from top to bottom.
+    #[config(default = true)]
+    rotate_vertical: bool,
+}
+
+/// Extract an upright crop for each box, in image coordinates as returned by
+/// [`adjust_coordinates`](crate::utils::adjust_coordinates). Crops are returned in the same order
+/// as `boxes`, and pixels outside of the image are set to `background`.
+///
+/// Quads may have their corners in any order and are perspective warped to a rectangle. Longer
+/// polygons describe curved text and are flattened with a piecewise affine warp, see
+/// [`crop_polygon`].
+///
+/// # Panics
+/// If a box has fewer than 4 points, or an odd number of points.
+pub fn crop_boxes<P, Q>(
+    image: &Image<P>,
+    boxes: &[Q],
+    background: P,
+    config: &CropConfig,
+) -> Vec<Image<P>>
+where
+    P: Pixel + Send + Sync,
+    <P as Pixel>::Subpixel: Into<f32> + Clamp<f32> + Send + Sync,
+    Q: AsRef<[Point<f32>]> + Sync,
+{
+    boxes
+        .par_iter()
+        .map(|bbox| match bbox.as_ref() {
+            &[a, b, c, d] => {
+                let quad = normalize_quad([a, b, c, d], config.rotate_vertical);
+                crop_quad(image, quad, background, config)
+            }
+            polygon => crop_polygon(image, polygon, background, config),
+        })
+        .collect()
+}
+
+/// Extract a crop for each box, with the text running along its direction, as estimated by
+/// [`text_directions`](crate::utils::characters::text_directions). Crops are keyed by detection
+/// id like the boxes, and boxes without a direction use the default
+/// [`TextDirection::Horizontal`]. Unlike [`crop_boxes`], this ignores `rotate_vertical`, so
+/// vertical text is rotated only when its direction says so.
+pub fn crop_oriented_boxes<P>(
+    image: &Image<P>,
+    boxes: &HashMap<u32, [Point<f32>; 4]>,
+    directions: &HashMap<u32, TextDirection>,
+    background: P,
+    config: &CropConfig,
+) -> HashMap<u32, Image<P>>
+where
+    P: Pixel + Send + Sync,
+    <P as Pixel>::Subpixel: Into<f32> + Clamp<f32> + Send + Sync,
+{
+    boxes
+        .par_iter()
+        .map(|(id, bbox)| {
+            let direction = directions.get(id).copied().unwrap_or_default();
+            let quad = orient_quad(*bbox, direction);
+            (*id, crop_quad(image, quad, background, config))
+        })
+        .collect()
+}
+
+/// Flatten a curved text polygon into a straight strip. The polygon holds `n` points along the top
+/// boundary from left to right, followed by `n` points along the bottom boundary from right to
+/// left, so that point `i` on the top is paired with point `2n - 1 - i` on the bottom. Each pair
+/// of neighbouring point pairs forms a segment that is split into two triangles, and each triangle
+/// is mapped affinely onto its part of the output rectangle.
+///
+/// # Panics
+/// If the polygon has fewer than 4 points, or an odd number of points.
+pub fn crop_polygon<P>(
+    image: &Image<P>,
+    polygon: &[Point<f32>],
+    background: P,
+    config: &CropConfig,
+) -> Image<P>
+where
+    P: Pixel + Send + Sync,
+    <P as Pixel>::Subpixel: Into<f32> + Clamp<f32> + Send + Sync,
+{
+    assert!(
+        polygon.len() >= 4 && polygon.len().is_multiple_of(2),
+        "Polygon must have an even number of at least 4 points"
+    );
+    let n = polygon.len() / 2;
+    let top = &polygon[..n];
+    let bottom: Vec<_> = polygon[n..].iter().rev().copied().collect();
+
+    let height = (0..n).map(|i| distance(top[i], bottom[i])).sum::<f32>() / n as f32;
+    let padding = config.padding * height;
+
+    // Column at which each boundary point pair ends up in the unpadded, unscaled output
+    let mut columns = vec![0.0];
+    for i in 0..n - 1 {
+        let width = (distance(top[i], top[i + 1]) + distance(bottom[i], bottom[i + 1])) / 2.0;
+        columns.push(columns[i] + width);
+    }
+    let width = columns[n - 1];
+
+    let out_width = width + 2.0 * padding + 1.0;
+    let out_height = height + 2.0 * padding + 1.0;
+    let scale = config.height.map_or(1.0, |h| h as f32 / out_height);
+
+    let mapping = |x: f32, y: f32| {
+        let u = x / scale - padding;
+        let t = (y / scale - padding) / height.max(1e-5);
+        // Segments past either end are extrapolated from the outermost one
+        let i = columns[1..n - 1].partition_point(|c| *c <= u);
+        let s = (u - columns[i]) / (columns[i + 1] - columns[i]).max(1e-5);
+
+        let (a, b, c, d) = (top[i], top[i + 1], bottom[i + 1], bottom[i]);
+        let weights = if s >= t {
+            // Upper right triangle: top left, top right, bottom right
+            [(a, 1.0 - s), (b, s - t), (c, t)]
+        } else {
+            // Lower left triangle: top left, bottom right, bottom left
+            [(a, 1.0 - t), (c, s), (d, t - s)]
+        };
+        weights
+            .iter()
+            .fold((0.0, 0.0), |(x, y), (p, w)| (x + p.x * w, y + p.y * w))
+    };
+
+    let out_width = ((out_width * scale).round() as u32).max(1);
+    let out_height = ((out_height * scale).round() as u32).max(1);
+    let mut out = Image::new(out_width, out_height);
+    warp_into_with(
+        image,
+        mapping,
+        Interpolation::Bilinear,
+        background,
+        &mut out,
+    );
+    out
+}
+
+/// Reorder the corners of a quad to clockwise order, starting at the top left corner of the text.
+/// The first edge is the one that points the most to the right. If `rotate_vertical` is set and
+/// the quad is taller than it is wide, the right edge becomes the top edge instead.
+pub fn normalize_quad(quad: [Point<f32>; 4], rotate_vertical: bool) -> [Point<f32>; 4] {
+    let quad = orient_quad(quad, TextDirection::Horizontal);
+
+    let length = |i: usize| distance(quad[i], quad[(i + 1) % 4]);
+    let width = length(0).max(length(2));
+    let height = length(1).max(length(3));
+    if rotate_vertical && height > width {
+        [1, 2, 3, 0].map(|i| quad[i])
+    } else {
+        quad
+    }
+}
+
+/// Reorder the corners of a quad to clockwise order, starting with the edge that points the most
+/// along `direction`, so that edge becomes the top of the crop
+pub fn orient_quad(quad: [Point<f32>; 4], direction: TextDirection) -> [Point<f32>; 4] {
+    let mut quad = quad;
+    // Positive signed area is clockwise with the y axis pointing down
+    if signed_area(&quad) < 0.0 {
+        quad.reverse();
+    }
+
+    let (sin, cos) = direction.angle().sin_cos();
+    let alignment = |i: usize| {
+        let e = quad[(i + 1) % 4] - quad[i];
+        (e.x * cos + e.y * sin) / (e.x.hypot(e.y) + 1e-5)
+    };
+    let start = (0..4)
+        .max_by(|a, b| alignment(*a).total_cmp(&alignment(*b)))
+        .unwrap();
+
+    [0, 1, 2, 3].map(|i| quad[(start + i) % 4])
+}
+
+/// Warp a normalized quad to an axis aligned crop
+fn crop_quad<P>(
+    image: &Image<P>,
+    quad: [Point<f32>; 4],
+    background: P,
+    config: &CropConfig,
+) -> Image<P>
+where
+    P: Pixel + Send + Sync,
+    <P as Pixel>::Subpixel: Into<f32> + Clamp<f32> + Send + Sync,
+{
+    let width = distance(quad[0], quad[1]).max(distance(quad[3], quad[2]));
+    let height = distance(quad[1], quad[2]).max(distance(quad[0], quad[3]));
+    let padding = config.padding * height;
+
+    // Corners are pixel centers, so a box spanning a distance `d` covers `d + 1` pixels
+    let out_width = width + 2.0 * padding + 1.0;
+    let out_height = height + 2.0 * padding + 1.0;
+    let scale = config.height.map_or(1.0, |h| h as f32 / out_height);
+
+    let (left, top) = (padding * scale, padding * scale);
+    let (right, bottom) = (left + width * scale, top + height * scale);
+    let from = quad.map(|p| (p.x, p.y));
+    let to = [(left, top), (right, top), (right, bottom), (left, bottom)];
+
+    let out_width = ((out_width * scale).round() as u32).max(1);
+    let out_height = ((out_height * scale).round() as u32).max(1);
+    let mut out = Image::new(out_width, out_height);
+    if let Some(projection) = Projection::from_control_points(from, to) {
+        warp_into(
+            image,
+            &projection,
+            Interpolation::Bilinear,
+            background,
+            &mut out,
+        );
+    }
+    out
+}
@@ -0,0 +1,179 @@
+use std::{collections::HashMap, f32::consts::FRAC_PI_2};
+
+use burn::config::Config;
+use image::Pixel;
+use imageproc::{
+    definitions::{Clamp, Image},
+    geometric_transformations::{rotate, Interpolation},
+    point::Point,
+};
+
+use crate::{crop::normalize_quad, geometry::distance, layout::Line};
+
+#[derive(Config, Debug)]
+pub struct DeskewConfig {
+    /// Largest skew in radians that is corrected. Larger angles are left to page rotation.
+    #[config(default = 0.2)]
+    max_angle: f32,
+    /// Angles within this many radians of the estimate count towards its confidence
+    #[config(default = 0.02)]
+    tolerance: f32,
+}
+
+/// Estimated skew of a page
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Skew {
+    /// Clockwise angle of the text in radians
+    pub angle: f32,
+    /// Share of the total weight of the measurements that agrees with `angle`, in `[0, 1]`
+    pub confidence: f32,
+}
+
+pub struct DeskewResult<P: Pixel> {
+    pub image: Image<P>,
+    pub skew: Skew,
+    /// Detections mapped to the deskewed image
+    pub boxes: HashMap<u32, [Point<f32>; 4]>,
+}
+
+/// Estimate the skew from the angle of the top and bottom edges of each box, weighted by the box
+/// area
+pub fn skew_from_boxes(boxes: &HashMap<u32, [Point<f32>; 4]>, config: &DeskewConfig) -> Skew {
+    let measurements = boxes
+        .values()
+        .map(|bbox| {
+            let [a, b, c, d] = normalize_quad(*bbox, false);
+            let (top, bottom) = (b - a, c - d);
+            let angle = (top.y + bottom.y).atan2(top.x + bottom.x);
+            let width = (top.x.hypot(top.y) + bottom.x.hypot(bottom.y)) / 2.0;
+            let height = (distance(a, d) + distance(b, c)) / 2.0;
+            (angle, width * height)
+        })
+        .collect();
+    estimate(measurements, config)
+}
+
+/// Estimate the skew from least squares line fits through the word centers of each line, as
+/// grouped by [`group_text_boxes`](crate::layout::group_text_boxes), weighted by the line length.
+/// Lines with a single word fall back to the angle of their box.
+pub fn skew_from_lines(
+    lines: &[Line],
+    boxes: &HashMap<u32, [Point<f32>; 4]>,
+    config: &DeskewConfig,
+) -> Skew {
+    let measurements = lines
+        .iter()
+        .map(|line| {
+            let length = distance(line.bbox[0], line.bbox[1]);
+            let centers: Vec<_> = line
+                .words
+                .iter()
+                .filter_map(|id| boxes.get(id))
+                .map(|bbox| {
+                    let x = bbox.iter().map(|p| p.x).sum::<f32>() / 4.0;
+                    let y = bbox.iter().map(|p| p.y).sum::<f32>() / 4.0;
+                    (x, y)
+                })
+                .collect();
+            if centers.len() < 2 {
+                return (line.angle, length);
+            }
+
+            let n = centers.len() as f32;
+            let mean_x = centers.iter().map(|c| c.0).sum::<f32>() / n;
+            let mean_y = centers.iter().map(|c| c.1).sum::<f32>() / n;
+            let (mut xx, mut xy) = (0.0, 0.0);
+            for (x, y) in &centers {
+                xx += (x - mean_x) * (x - mean_x);
+                xy += (x - mean_x) * (y - mean_y);
+            }
+            (xy.atan2(xx), length)
+        })
+        .collect();
+    estimate(measurements, config)
+}
+
+/// Rotate `image` about its center to undo `skew`, and map `boxes` along with it. Skews larger
+/// than `max_angle` are not corrected.
+pub fn deskew<P>(
+    image: &Image<P>,
+    boxes: HashMap<u32, [Point<f32>; 4]>,
+    skew: Skew,
+    background: P,
+    config: &DeskewConfig,
+) -> DeskewResult<P>
+where
+    P: Pixel + Send + Sync,
+    <P as Pixel>::Subpixel: Into<f32> + Clamp<f32> + Send + Sync,
+{
+    if skew.angle.abs() > config.max_angle || skew.angle == 0.0 {
+        return DeskewResult {
+            image: image.clone(),
+            skew,
+            boxes,
+        };
+    }
+
+    let (width, height) = image.dimensions();
+    let center = ((width as f32 - 1.0) / 2.0, (height as f32 - 1.0) / 2.0);
+    let theta = -skew.angle;
+    let image = rotate(image, center, theta, Interpolation::Bilinear, background);
+
+    let (sin, cos) = theta.sin_cos();
+    let mut boxes = boxes;
+    for p in boxes.values_mut().flatten() {
+        let (x, y) = (p.x - center.0, p.y - center.1);
+        *p = Point::new(center.0 + cos * x - sin * y, center.1 + sin * x + cos * y);
+    }
+
+    DeskewResult { image, skew, boxes }
+}
+
+/// Weighted median of `(angle, weight)` measurements within `max_angle`
+fn estimate(measurements: Vec<(f32, f32)>, config: &DeskewConfig) -> Skew {
+    // Measurements beyond `max_angle` still count against the confidence
+    let total: f32 = measurements.iter().map(|m| m.1).sum();
+    let mut measurements: Vec<_> = measurements
+        .into_iter()
+        .map(|(angle, weight)| (wrap(angle), weight))
+        .filter(|(angle, weight)| angle.abs() <= config.max_angle && *weight > 0.0)
+        .collect();
+    let within: f32 = measurements.iter().map(|m| m.1).sum();
+    if within == 0.0 {
+        return Skew {
+            angle: 0.0,
+            confidence: 0.0,
+        };
+    }
+
+    measurements.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut cumulative = 0.0;
+    let angle = measurements
+        .iter()
+        .find(|(_, weight)| {
+            cumulative += weight;
+            cumulative >= within / 2.0
+        })
+        .unwrap()
+        .0;
+
+    let agreeing: f32 = measurements
+        .iter()
+        .filter(|m| (m.0 - angle).abs() <= config.tolerance)
+        .map(|m| m.1)
+        .sum();
+    Skew {
+        angle,
+        confidence: agreeing / total,
+    }
+}
+
+/// Wrap a line angle to `(-pi/2, pi/2]`, since lines have no direction
+fn wrap(angle: f32) -> f32 {
+    let angle = angle.rem_euclid(std::f32::consts::PI);
+    if angle > FRAC_PI_2 {
+        angle - std::f32::consts::PI
+    } else {
+        angle
+    }
+}
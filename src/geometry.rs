@@ -0,0 +1,194 @@
+use imageproc::point::Point;
+
+/// Euclidean length of a vector
+pub fn norm(p: Point<f32>) -> f32 {
+    p.x.hypot(p.y)
+}
+
+/// Euclidean distance between two points
+pub fn distance(a: Point<f32>, b: Point<f32>) -> f32 {
+    norm(b - a)
+}
+
+/// Area of a polygon, positive if its points are in clockwise order in image coordinates, where
+/// the y axis points down
+pub fn signed_area(polygon: &[Point<f32>]) -> f32 {
+    let n = polygon.len();
+    let sum: f32 = (0..n)
+        .map(|i| {
+            let (a, b) = (polygon[i], polygon[(i + 1) % n]);
+            a.x * b.y - b.x * a.y
+        })
+        .sum();
+    sum / 2.0
+}
+
+pub fn area(polygon: &[Point<f32>]) -> f32 {
+    signed_area(polygon).abs()
+}
+
+pub fn perimeter(polygon: &[Point<f32>]) -> f32 {
+    let n = polygon.len();
+    (0..n)
+        .map(|i| distance(polygon[i], polygon[(i + 1) % n]))
+        .sum()
+}
+
+/// Whether `p` lies inside a simple polygon, with the even-odd rule
+pub fn contains(polygon: &[Point<f32>], p: Point<f32>) -> bool {
+    let n = polygon.len();
+    let mut inside = false;
+    for i in 0..n {
+        let (a, b) = (polygon[i], polygon[(i + 1) % n]);
+        if (a.y > p.y) != (b.y > p.y) && p.x < a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Clip `subject` to the convex polygon `clip` with the Sutherland-Hodgman algorithm. `subject`
+/// may be concave, but then the result can contain zero width bridges along the clip edges, which
+/// don't affect its area.
+pub fn clip_polygon(subject: &[Point<f32>], clip: &[Point<f32>]) -> Vec<Point<f32>> {
+    let orientation = signed_area(clip).signum();
+    // Positive on the inner side of the edge from `a` to `b`
+    let side = |a: Point<f32>, b: Point<f32>, p: Point<f32>| {
+        orientation * ((b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x))
+    };
+
+    let mut output = subject.to_vec();
+    for i in 0..clip.len() {
+        if output.is_empty() {
+            break;
+        }
+        let (a, b) = (clip[i], clip[(i + 1) % clip.len()]);
+        let input = std::mem::take(&mut output);
+        for j in 0..input.len() {
+            let (p, q) = (input[j], input[(j + 1) % input.len()]);
+            let (sp, sq) = (side(a, b, p), side(a, b, q));
+            if sp >= 0.0 {
+                output.push(p);
+            }
+            if (sp >= 0.0) != (sq >= 0.0) {
+                let t = sp / (sp - sq);
+                output.push(Point::new(p.x + t * (q.x - p.x), p.y + t * (q.y - p.y)));
+            }
+        }
+    }
+    output
+}
+
+/// Clip a polygon to the bounds of an image of the given size, with pixel centers at integer
+/// coordinates
+pub fn clip_to_image(polygon: &[Point<f32>], (width, height): (u32, u32)) -> Vec<Point<f32>> {
+    let (right, bottom) = (width as f32 - 1.0, height as f32 - 1.0);
+    let bounds = [
+        Point::new(0.0, 0.0),
+        Point::new(right, 0.0),
+        Point::new(right, bottom),
+        Point::new(0.0, bottom),
+    ];
+    clip_polygon(polygon, &bounds)
+}
+
+/// Area of the intersection of two convex polygons
+pub fn convex_intersection_area(a: &[Point<f32>], b: &[Point<f32>]) -> f32 {
+    area(&clip_polygon(a, b))
+}
+
+/// Area of the intersection of two simple polygons, which may be concave and in either winding
+/// order. Both polygons are split into a fan of signed triangles, whose indicator functions sum up
+/// to that of the polygon, so the intersection area is the signed sum of the pairwise triangle
+/// intersections.
+pub fn intersection_area(a: &[Point<f32>], b: &[Point<f32>]) -> f32 {
+    let fan = |polygon: &[Point<f32>]| -> Vec<([Point<f32>; 3], f32)> {
+        (1..polygon.len().saturating_sub(1))
+            .map(|i| {
+                let triangle = [polygon[0], polygon[i], polygon[i + 1]];
+                (triangle, signed_area(&triangle).signum())
+            })
+            .filter(|(_, sign)| *sign != 0.0)
+            .collect()
+    };
+    let (fan_a, fan_b) = (fan(a), fan(b));
+    let sign_a = signed_area(a).signum();
+    let sign_b = signed_area(b).signum();
+
+    let mut sum = 0.0;
+    for (ta, sa) in &fan_a {
+        for (tb, sb) in &fan_b {
+            sum += sa * sb * convex_intersection_area(ta, tb);
+        }
+    }
+    (sum * sign_a * sign_b).max(0.0)
+}
+
+/// Intersection over union of two convex polygons
+pub fn convex_iou(a: &[Point<f32>], b: &[Point<f32>]) -> f32 {
+    iou_from(convex_intersection_area(a, b), a, b)
+}
+
+/// Intersection over union of two polygons, which may be concave
+pub fn iou(a: &[Point<f32>], b: &[Point<f32>]) -> f32 {
+    iou_from(intersection_area(a, b), a, b)
+}
+
+fn iou_from(intersection: f32, a: &[Point<f32>], b: &[Point<f32>]) -> f32 {
+    let union = area(a) + area(b) - intersection;
+    if union <= 0.0 {
+        0.0
+    } else {
+        intersection / union
+    }
+}
+
+/// Maximum length of a miter join in [`offset_polygon`], relative to the offset distance. Sharper
+/// corners are cut off.
+const MITER_LIMIT: f32 = 2.0;
+
+/// Move every edge of a polygon outwards by `distance`, or inwards if it is negative, and join the
+/// moved edges with miter joins. Corners sharper than the miter limit are beveled. Large inward
+/// offsets of concave polygons can self intersect, like any simple offsetting.
+pub fn offset_polygon(polygon: &[Point<f32>], distance: f32) -> Vec<Point<f32>> {
+    let n = polygon.len();
+    if n < 3 || distance == 0.0 {
+        return polygon.to_vec();
+    }
+    let orientation = signed_area(polygon).signum();
+    let normal = |i: usize| {
+        let e = polygon[(i + 1) % n] - polygon[i];
+        let length = norm(e).max(1e-6);
+        // Outward normal for clockwise polygons in image coordinates
+        Point::new(orientation * e.y / length, -orientation * e.x / length)
+    };
+
+    let mut out = Vec::with_capacity(n);
+    for (i, p) in polygon.iter().enumerate() {
+        let (n0, n1) = (normal((i + n - 1) % n), normal(i));
+        let bisector = n0 + n1;
+        // Squared cosine of half the angle between the normals
+        let cos_squared = (1.0 + n0.x * n1.x + n0.y * n1.y) / 2.0;
+        if cos_squared * MITER_LIMIT * MITER_LIMIT >= 1.0 {
+            // The miter lies along the bisector, at `distance / cos` from the corner
+            let scale = distance / (2.0 * cos_squared);
+            out.push(Point::new(
+                p.x + bisector.x * scale,
+                p.y + bisector.y * scale,
+            ));
+        } else {
+            out.push(Point::new(p.x + n0.x * distance, p.y + n0.y * distance));
+            out.push(Point::new(p.x + n1.x * distance, p.y + n1.y * distance));
+        }
+    }
+    out
+}
+
+/// Expand a polygon as in DBNet, by the distance `area * ratio / perimeter`
+pub fn unclip(polygon: &[Point<f32>], ratio: f32) -> Vec<Point<f32>> {
+    let perimeter = perimeter(polygon);
+    if perimeter == 0.0 {
+        return polygon.to_vec();
+    }
+    offset_polygon(polygon, area(polygon) * ratio / perimeter)
+}
@@ -0,0 +1,83 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use burn::{prelude::Backend, tensor::Tensor};
+use image::{ImageBuffer, ImageResult, Luma};
+use imageproc::point::Point;
+
+use crate::{image_util::ImageTransform, npy::write_npy};
+
+/// Scores as an `f32` image
+pub type ScoreImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Region and link scores at the resolution of the original image
+pub struct ScoreImages {
+    pub text: ScoreImage,
+    pub link: ScoreImage,
+}
+
+/// Read back the region and link score maps, and resample them to the original image that
+/// `transform` maps back to. Padding is dropped, and pixels outside the crop of the transform are 0.
+pub fn score_images<B: Backend>(
+    text_map: Tensor<B, 4>,
+    link_map: Tensor<B, 4>,
+    transform: &ImageTransform,
+) -> ScoreImages {
+    let [_, height, width, _] = text_map.shape().dims::<4>();
+    let size = height * width;
+    let data = Tensor::cat(vec![text_map.reshape([size]), link_map.reshape([size])], 0);
+    let data = data.into_data().convert::<f32>().to_vec::<f32>().unwrap();
+    let (text, link) = data.split_at(size);
+    ScoreImages {
+        text: upsample_scores(text, (width, height), transform),
+        link: upsample_scores(link, (width, height), transform),
+    }
+}
+
+/// Sample a heatmap with bilinear interpolation at every pixel of the original image
+pub fn upsample_scores(
+    scores: &[f32],
+    (width, height): (usize, usize),
+    transform: &ImageTransform,
+) -> ScoreImage {
+    assert_eq!(scores.len(), width * height);
+    let (image_width, image_height) = transform.image_size;
+    let crop = transform.crop;
+    let at = |x: usize, y: usize| scores[y.min(height - 1) * width + x.min(width - 1)];
+
+    ScoreImage::from_par_fn(image_width, image_height, |x, y| {
+        let (xi, yi) = (x as i32, y as i32);
+        if xi < crop.left() || xi > crop.right() || yi < crop.top() || yi > crop.bottom() {
+            return Luma([0.0]);
+        }
+
+        let p = transform.to_heatmap(Point::new(x as f32, y as f32));
+        let (px, py) = (p.x.max(0.0), p.y.max(0.0));
+        let (x0, y0) = (px.floor() as usize, py.floor() as usize);
+        let (fx, fy) = (px.fract(), py.fract());
+        let top = at(x0, y0) * (1.0 - fx) + at(x0 + 1, y0) * fx;
+        let bottom = at(x0, y0 + 1) * (1.0 - fx) + at(x0 + 1, y0 + 1) * fx;
+        Luma([top * (1.0 - fy) + bottom * fy])
+    })
+}
+
+/// Save scores to `path`, in the format given by its extension. `npy` stores the raw values as a
+/// `height x width` array, any image format stores 16 bit grayscale with scores in `[0, 1]`
+/// mapped to the full range, e.g. `png` or `tif`.
+pub fn save_scores(scores: &ScoreImage, path: impl AsRef<Path>) -> ImageResult<()> {
+    let path = path.as_ref();
+    let is_npy = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("npy"));
+    if is_npy {
+        let (width, height) = scores.dimensions();
+        let writer = BufWriter::new(File::create(path)?);
+        write_npy(writer, &[height as usize, width as usize], scores.as_raw())?;
+        return Ok(());
+    }
+
+    let image = ImageBuffer::<Luma<u16>, _>::from_fn(scores.width(), scores.height(), |x, y| {
+        let value = scores.get_pixel(x, y)[0].clamp(0.0, 1.0);
+        Luma([(value * u16::MAX as f32).round() as u16])
+    });
+    image.save(path)
+}
@@ -7,10 +7,11 @@ use burn::{
     tensor::{
         module::interpolate,
         ops::{InterpolateMode, InterpolateOptions},
-        Device, ElementConversion, Shape, Tensor, TensorData,
+        Device, ElementConversion, Int, Shape, Tensor, TensorData,
     },
 };
 use image::{DynamicImage, Rgb, RgbImage};
+use imageproc::{point::Point, rect::Rect};
 
 #[derive(Config, Debug)]
 pub struct NormalizeMeanVarianceConfig {
@@ -48,6 +49,191 @@ pub struct ResizeResult<B: Backend> {
     pub image: Tensor<B, 4>,
     pub ratio: f32,
     pub size_heatmap: (usize, usize),
+    /// Maps heatmap coordinates back to the original image
+    pub transform: ImageTransform,
+}
+
+/// Clockwise rotation by a multiple of 90 degrees, matching `image::imageops::rotate90` etc.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Rotation {
+    #[default]
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    pub fn inverse(self) -> Self {
+        match self {
+            Rotation::Rotate0 => Rotation::Rotate0,
+            Rotation::Rotate90 => Rotation::Rotate270,
+            Rotation::Rotate180 => Rotation::Rotate180,
+            Rotation::Rotate270 => Rotation::Rotate90,
+        }
+    }
+
+    /// Clockwise angle in degrees
+    pub fn degrees(self) -> u32 {
+        match self {
+            Rotation::Rotate0 => 0,
+            Rotation::Rotate90 => 90,
+            Rotation::Rotate180 => 180,
+            Rotation::Rotate270 => 270,
+        }
+    }
+
+    /// Rotation by `self` followed by `other`
+    pub fn then(self, other: Rotation) -> Self {
+        match (self.degrees() + other.degrees()) % 360 {
+            0 => Rotation::Rotate0,
+            90 => Rotation::Rotate90,
+            180 => Rotation::Rotate180,
+            _ => Rotation::Rotate270,
+        }
+    }
+
+    /// Rotate an image
+    pub fn apply(self, image: &DynamicImage) -> DynamicImage {
+        match self {
+            Rotation::Rotate0 => image.clone(),
+            Rotation::Rotate90 => image.rotate90(),
+            Rotation::Rotate180 => image.rotate180(),
+            Rotation::Rotate270 => image.rotate270(),
+        }
+    }
+
+    /// Size of an image of `size` after rotating it
+    pub fn rotated_size(self, (width, height): (u32, u32)) -> (u32, u32) {
+        match self {
+            Rotation::Rotate0 | Rotation::Rotate180 => (width, height),
+            Rotation::Rotate90 | Rotation::Rotate270 => (height, width),
+        }
+    }
+
+    /// Position of the pixel at `p` after rotating an image of `size`
+    pub fn rotate_point(self, p: Point<f32>, (width, height): (u32, u32)) -> Point<f32> {
+        let (w, h) = (width as f32 - 1.0, height as f32 - 1.0);
+        match self {
+            Rotation::Rotate0 => p,
+            Rotation::Rotate90 => Point::new(h - p.y, p.x),
+            Rotation::Rotate180 => Point::new(w - p.x, h - p.y),
+            Rotation::Rotate270 => Point::new(p.y, w - p.x),
+        }
+    }
+}
+
+/// Transform from original image coordinates to heatmap coordinates. The original image is
+/// cropped, rotated, scaled per axis and padded to form the network input, and the heatmap has
+/// one pixel per `stride` input pixels.
+///
+/// All coordinates are pixel coordinates, with pixel centers at integer positions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageTransform {
+    /// Size of the original image
+    pub image_size: (u32, u32),
+    /// Region of the original image that was used
+    pub crop: Rect,
+    /// Rotation applied to the crop
+    pub rotation: Rotation,
+    /// Scale from the rotated crop to the network input. The resize aligns corners like burn's
+    /// bilinear `interpolate`, so the first and last pixel centers of both stay in place.
+    pub scale: (f32, f32),
+    /// Padding added to the left and top of the network input
+    pub padding: (u32, u32),
+    /// Size of a heatmap pixel in network input pixels
+    pub stride: f32,
+}
+
+impl ImageTransform {
+    /// Transform for an image of `size` that was resized to `target` and had the network applied
+    pub fn resized(size: (u32, u32), target: (usize, usize)) -> Self {
+        let (width, height) = size;
+        let (target_w, target_h) = target;
+        Self {
+            image_size: size,
+            crop: Rect::at(0, 0).of_size(width, height),
+            rotation: Rotation::Rotate0,
+            scale: (
+                align_corners_scale(width as usize, target_w),
+                align_corners_scale(height as usize, target_h),
+            ),
+            padding: (0, 0),
+            stride: 2.0,
+        }
+    }
+
+    /// The transformed image was `rotation` applied to the original. Must be applied before
+    /// [`ImageTransform::with_crop`].
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        assert_eq!(self.rotation, Rotation::Rotate0, "Rotation is already set");
+        let size = (self.crop.width(), self.crop.height());
+        assert_eq!(
+            size, self.image_size,
+            "Rotation must be set before the crop"
+        );
+
+        let (width, height) = rotation.inverse().rotated_size(size);
+        self.image_size = (width, height);
+        self.crop = Rect::at(0, 0).of_size(width, height);
+        self.rotation = rotation;
+        self
+    }
+
+    /// The transformed image was cropped from an image of `image_size` at `crop`
+    pub fn with_crop(mut self, crop: Rect, image_size: (u32, u32)) -> Self {
+        assert_eq!(
+            (crop.width(), crop.height()),
+            self.image_size,
+            "Crop size must match the image size"
+        );
+        self.image_size = image_size;
+        self.crop = crop;
+        self
+    }
+
+    fn crop_size(&self) -> (u32, u32) {
+        (self.crop.width(), self.crop.height())
+    }
+
+    /// Map a point in the original image to the heatmap
+    pub fn to_heatmap(&self, p: Point<f32>) -> Point<f32> {
+        let p = Point::new(p.x - self.crop.left() as f32, p.y - self.crop.top() as f32);
+        let p = self.rotation.rotate_point(p, self.crop_size());
+        let x = p.x * self.scale.0 + self.padding.0 as f32;
+        let y = p.y * self.scale.1 + self.padding.1 as f32;
+        Point::new((x + 0.5) / self.stride - 0.5, (y + 0.5) / self.stride - 0.5)
+    }
+
+    /// Map a point in the heatmap to the original image. Exact inverse of
+    /// [`ImageTransform::to_heatmap`].
+    pub fn to_image(&self, p: Point<f32>) -> Point<f32> {
+        let x = (p.x + 0.5) * self.stride - 0.5 - self.padding.0 as f32;
+        let y = (p.y + 0.5) * self.stride - 0.5 - self.padding.1 as f32;
+        let p = Point::new(x / self.scale.0, y / self.scale.1);
+        let rotated_size = self.rotation.rotated_size(self.crop_size());
+        let p = self.rotation.inverse().rotate_point(p, rotated_size);
+        Point::new(p.x + self.crop.left() as f32, p.y + self.crop.top() as f32)
+    }
+
+    /// Clamp a point to the bounds of the original image
+    pub fn clip(&self, p: Point<f32>) -> Point<f32> {
+        let (width, height) = self.image_size;
+        Point::new(
+            p.x.clamp(0.0, width.saturating_sub(1) as f32),
+            p.y.clamp(0.0, height.saturating_sub(1) as f32),
+        )
+    }
+}
+
+/// Scale of a resize from `input` to `output` pixels with aligned corners, where output pixel `i`
+/// samples input pixel `i * (input - 1) / (output - 1)`. A single pixel is kept in place.
+fn align_corners_scale(input: usize, output: usize) -> f32 {
+    if input <= 1 || output <= 1 {
+        1.0
+    } else {
+        (output - 1) as f32 / (input - 1) as f32
+    }
 }
 
 pub fn resize_aspect_ratio<B: Backend>(
@@ -67,6 +253,27 @@ pub fn resize_aspect_ratio<B: Backend>(
     );
     let tensor = Tensor::from_data(image_f32, device).permute([0, 3, 1, 2]);
 
+    let (ratio, target_h, target_w) = target_size(height, width, square_size, mag_ratio);
+
+    let image = interpolate(
+        tensor,
+        [target_h, target_w],
+        InterpolateOptions::new(InterpolateMode::Bilinear),
+    );
+
+    let (padded, size_heatmap) = pad_32(image, target_h, target_w);
+
+    println!("Resize took {:?}", Instant::now() - start);
+
+    ResizeResult {
+        image: padded,
+        ratio,
+        size_heatmap,
+        transform: ImageTransform::resized((width, height), (target_w, target_h)),
+    }
+}
+
+fn target_size(height: u32, width: u32, square_size: usize, mag_ratio: f32) -> (f32, usize, usize) {
     let max = height.max(width) as f32;
 
     let mut target_size = mag_ratio * max;
@@ -78,13 +285,15 @@ pub fn resize_aspect_ratio<B: Backend>(
 
     let target_h = (height as f32 * ratio) as usize;
     let target_w = (width as f32 * ratio) as usize;
+    (ratio, target_h, target_w)
+}
 
-    let image = interpolate(
-        tensor,
-        [target_h, target_w],
-        InterpolateOptions::new(InterpolateMode::Bilinear),
-    );
-
+/// Pad to a multiple of 32 and return the padded image along with the heatmap size
+fn pad_32<B: Backend>(
+    image: Tensor<B, 4>,
+    target_h: usize,
+    target_w: usize,
+) -> (Tensor<B, 4>, (usize, usize)) {
     let target_h_32 = target_h.div_ceil(32) * 32;
     let target_w_32 = target_w.div_ceil(32) * 32;
 
@@ -93,14 +302,130 @@ pub fn resize_aspect_ratio<B: Backend>(
         0.0.elem(),
     );
 
-    let size_heatmap = (target_h_32 / 2, target_w_32 / 2);
+    (padded, (target_h_32 / 2, target_w_32 / 2))
+}
 
-    println!("Resize took {:?}", Instant::now() - start);
+#[derive(Config, Debug)]
+pub struct PreprocessConfig {
+    /// Maximum side length for the scaled image
+    #[config(default = 1280)]
+    square_size: usize,
+    /// Magnification ratio for the input image
+    #[config(default = 1.5)]
+    mag_ratio: f32,
+    #[config(default = "NormalizeMeanVarianceConfig::new()")]
+    normalize: NormalizeMeanVarianceConfig,
+}
 
-    ResizeResult {
-        image: padded,
-        ratio,
-        size_heatmap,
+impl PreprocessConfig {
+    pub fn init<B: Backend>(&self, device: &B::Device) -> Preprocess<B> {
+        // `(x / 255 - mean) / variance` folded into a single multiply-add on the raw bytes
+        let NormalizeMeanVarianceConfig { mean, variance } = self.normalize;
+        let scale = variance.map(|v| 1.0 / (255.0 * v));
+        let bias = [0, 1, 2].map(|c| -mean[c] / variance[c]);
+        let scale = TensorData::new(scale.to_vec(), Shape::new([1, 3, 1, 1]));
+        let bias = TensorData::new(bias.to_vec(), Shape::new([1, 3, 1, 1]));
+
+        Preprocess {
+            scale: Tensor::from_data(scale, device),
+            bias: Tensor::from_data(bias, device),
+            square_size: self.square_size,
+            mag_ratio: self.mag_ratio,
+            device: device.clone(),
+        }
+    }
+}
+
+/// Preprocessing that runs entirely on the device, starting from the raw 8 bit image. Replaces
+/// [`resize_aspect_ratio`] followed by [`NormalizeMeanVariance`].
+#[derive(Debug, Clone)]
+pub struct Preprocess<B: Backend> {
+    scale: Tensor<B, 4>,
+    bias: Tensor<B, 4>,
+    square_size: usize,
+    mag_ratio: f32,
+    device: B::Device,
+}
+
+impl<B: Backend> Preprocess<B> {
+    /// Preprocess an image. RGB and RGBA images are uploaded as is, anything else is converted to
+    /// RGB first.
+    pub fn forward(&self, img: &DynamicImage) -> ResizeResult<B> {
+        match img {
+            DynamicImage::ImageRgb8(img) => {
+                self.forward_raw(img.as_raw(), img.width(), img.height(), 3)
+            }
+            DynamicImage::ImageRgba8(img) => {
+                self.forward_raw(img.as_raw(), img.width(), img.height(), 4)
+            }
+            img => {
+                let img = img.to_rgb8();
+                self.forward_raw(img.as_raw(), img.width(), img.height(), 3)
+            }
+        }
+    }
+
+    /// Preprocess an interleaved 8 bit image with `channels` channels, of which the first three
+    /// must be RGB. Returns the normalized image padded to a multiple of 32.
+    pub fn forward_raw(
+        &self,
+        pixels: &[u8],
+        width: u32,
+        height: u32,
+        channels: usize,
+    ) -> ResizeResult<B> {
+        let (height, width) = (height as usize, width as usize);
+        assert_eq!(pixels.len(), height * width * channels);
+
+        // Pack three bytes into each int so the upload is about the size of the raw buffer. Only
+        // using the low 24 bits keeps the values positive so they can be unpacked with division.
+        let words: Vec<i32> = pixels
+            .chunks(3)
+            .map(|bytes| {
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |word, b| (word << 8) | *b as i32)
+            })
+            .collect();
+        let num_words = words.len();
+        let words =
+            Tensor::<B, 1, Int>::from_data(TensorData::new(words, [num_words]), &self.device);
+        let bytes = Tensor::stack::<2>(
+            vec![
+                words.clone() % 256,
+                (words.clone() / 256) % 256,
+                words / 65536,
+            ],
+            1,
+        );
+        let image = bytes
+            .reshape([num_words * 3])
+            .narrow(0, 0, pixels.len())
+            .reshape([1, height, width, channels])
+            .narrow(3, 0, 3)
+            .float()
+            .permute([0, 3, 1, 2]);
+
+        let (ratio, target_h, target_w) = target_size(
+            height as u32,
+            width as u32,
+            self.square_size,
+            self.mag_ratio,
+        );
+        let image = interpolate(
+            image,
+            [target_h, target_w],
+            InterpolateOptions::new(InterpolateMode::Bilinear),
+        );
+        let (padded, size_heatmap) = pad_32(image, target_h, target_w);
+
+        ResizeResult {
+            image: padded * self.scale.clone() + self.bias.clone(),
+            ratio,
+            size_heatmap,
+            transform: ImageTransform::resized((width as u32, height as u32), (target_w, target_h)),
+        }
     }
 }
 
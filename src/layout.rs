@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+
+use burn::config::Config;
+use imageproc::point::Point;
+
+use crate::{crop::normalize_quad, geometry::distance, utils::characters::TextDirection};
+
+#[derive(Config, Debug)]
+pub struct GroupConfig {
+    /// Maximum angle difference in radians between words on the same line, and between lines in
+    /// the same paragraph
+    #[config(default = 0.1)]
+    angle_ths: f32,
+    /// Maximum offset between word centers perpendicular to the line, relative to the word height
+    #[config(default = 0.5)]
+    ycenter_ths: f32,
+    /// Maximum height difference between words on the same line, relative to the word height
+    #[config(default = 0.5)]
+    height_ths: f32,
+    /// Maximum horizontal gap between words on the same line, relative to the word height
+    #[config(default = 1.0)]
+    width_ths: f32,
+    /// Maximum vertical gap between lines in the same paragraph, relative to the line height
+    #[config(default = 1.0)]
+    paragraph_ths: f32,
+}
+
+/// A line of words. `words` holds the detection ids from left to right.
+#[derive(Clone, Debug)]
+pub struct Line {
+    pub bbox: [Point<f32>; 4],
+    pub angle: f32,
+    pub words: Vec<u32>,
+}
+
+/// A block of lines, from top to bottom
+#[derive(Clone, Debug)]
+pub struct Paragraph {
+    pub bbox: [Point<f32>; 4],
+    pub angle: f32,
+    pub lines: Vec<Line>,
+}
+
+/// Group word boxes, as returned by [`get_det_boxes`](crate::utils::get_det_boxes), into lines and
+/// paragraphs. Boxes may be slanted, in which case lines follow the angle of their words.
+/// Paragraphs are returned from top to bottom.
+pub fn group_text_boxes(
+    boxes: &HashMap<u32, [Point<f32>; 4]>,
+    config: &GroupConfig,
+) -> Vec<Paragraph> {
+    let mut words: Vec<_> = boxes
+        .iter()
+        .map(|(id, bbox)| Word::new(*id, *bbox))
+        .collect();
+    if words.is_empty() {
+        return Vec::new();
+    }
+
+    // Process words along the dominant direction of the page, so lines grow from left to right
+    let angle = median(words.iter().map(|w| w.angle).collect());
+    let (dir, normal) = axes(angle);
+    words.sort_by(|a, b| {
+        dot(a.center, dir)
+            .total_cmp(&dot(b.center, dir))
+            .then(a.id.cmp(&b.id))
+    });
+
+    let mut lines: Vec<Vec<Word>> = Vec::new();
+    for word in words {
+        let best = lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                let last = line.last().unwrap();
+                last.continues_with(&word, config).map(|offset| (i, offset))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        match best {
+            Some((i, _)) => lines[i].push(word),
+            None => lines.push(vec![word]),
+        }
+    }
+
+    let mut lines: Vec<_> = lines.into_iter().map(Line::from_words).collect();
+    lines.sort_by(|a, b| {
+        let a = dot(a.bbox[0], normal);
+        let b = dot(b.bbox[0], normal);
+        a.total_cmp(&b)
+    });
+
+    let mut paragraphs: Vec<Vec<Line>> = Vec::new();
+    for line in lines {
+        let target = paragraphs
+            .iter()
+            .position(|paragraph| follows(paragraph.last().unwrap(), &line, config));
+        match target {
+            Some(i) => paragraphs[i].push(line),
+            None => paragraphs.push(vec![line]),
+        }
+    }
+
+    paragraphs
+        .into_iter()
+        .map(|lines| {
+            let angle = median(lines.iter().map(|l| l.angle).collect());
+            let corners: Vec<_> = lines.iter().flat_map(|l| l.bbox).collect();
+            Paragraph {
+                bbox: Extent::of(&corners, angle).corners(angle),
+                angle,
+                lines,
+            }
+        })
+        .collect()
+}
+
+impl Line {
+    fn from_words(words: Vec<Word>) -> Self {
+        let angle = median(words.iter().map(|w| w.angle).collect());
+        let corners: Vec<_> = words.iter().flat_map(|w| w.bbox).collect();
+        Line {
+            bbox: Extent::of(&corners, angle).corners(angle),
+            angle,
+            words: words.iter().map(|w| w.id).collect(),
+        }
+    }
+
+    fn height(&self) -> f32 {
+        distance(self.bbox[0], self.bbox[3])
+    }
+}
+
+/// Whether `next` continues the paragraph ending with `last`
+fn follows(last: &Line, next: &Line, config: &GroupConfig) -> bool {
+    if angle_diff(last.angle, next.angle) > config.angle_ths {
+        return false;
+    }
+    let a = Extent::of(&last.bbox, last.angle);
+    let b = Extent::of(&next.bbox, last.angle);
+    let height = (last.height() + next.height()) / 2.0;
+    let gap = b.top - a.bottom;
+    let overlap = a.right.min(b.right) - a.left.max(b.left);
+    gap <= config.paragraph_ths * height && overlap > 0.0
+}
+
+struct Word {
+    id: u32,
+    /// Corners in clockwise order starting at the top left
+    bbox: [Point<f32>; 4],
+    center: Point<f32>,
+    angle: f32,
+    height: f32,
+}
+
+impl Word {
+    fn new(id: u32, bbox: [Point<f32>; 4]) -> Self {
+        let [a, b, c, d] = normalize_quad(bbox, false);
+        let center = Point::new((a.x + b.x + c.x + d.x) / 4.0, (a.y + b.y + c.y + d.y) / 4.0);
+        let top = b - a;
+        let bottom = c - d;
+        let angle = (top.y + bottom.y).atan2(top.x + bottom.x);
+        let height = (distance(a, d) + distance(b, c)) / 2.0;
+        Word {
+            id,
+            bbox: [a, b, c, d],
+            center,
+            angle,
+            height,
+        }
+    }
+
+    /// If `next` can follow this word on the same line, returns its offset perpendicular to the
+    /// line, used to pick the best line when several match
+    fn continues_with(&self, next: &Word, config: &GroupConfig) -> Option<f32> {
+        if angle_diff(self.angle, next.angle) > config.angle_ths {
+            return None;
+        }
+        let height = (self.height + next.height) / 2.0;
+        if (self.height - next.height).abs() > config.height_ths * height {
+            return None;
+        }
+
+        let (dir, normal) = axes(self.angle);
+        let offset = dot(next.center - self.center, normal).abs();
+        if offset > config.ycenter_ths * height {
+            return None;
+        }
+
+        let a = Extent::of(&self.bbox, self.angle);
+        let b = Extent::of(&next.bbox, self.angle);
+        let gap = b.left - a.right;
+        // Allow some overlap between neighbours, but not a word starting before this one
+        let behind = dot(next.center - self.center, dir) < 0.0;
+        (!behind && gap <= config.width_ths * height).then_some(offset)
+    }
+}
+
+/// Bounds of a set of points in a frame rotated by some angle
+struct Extent {
+    left: f32,
+    right: f32,
+    top: f32,
+    bottom: f32,
+}
+
+impl Extent {
+    fn of(points: &[Point<f32>], angle: f32) -> Self {
+        let (dir, normal) = axes(angle);
+        let mut extent = Extent {
+            left: f32::MAX,
+            right: f32::MIN,
+            top: f32::MAX,
+            bottom: f32::MIN,
+        };
+        for p in points {
+            let (u, v) = (dot(*p, dir), dot(*p, normal));
+            extent.left = extent.left.min(u);
+            extent.right = extent.right.max(u);
+            extent.top = extent.top.min(v);
+            extent.bottom = extent.bottom.max(v);
+        }
+        extent
+    }
+
+    /// Corners in image coordinates, clockwise starting at the top left
+    fn corners(&self, angle: f32) -> [Point<f32>; 4] {
+        let (dir, normal) = axes(angle);
+        let at = |u: f32, v: f32| Point::new(dir.x * u + normal.x * v, dir.y * u + normal.y * v);
+        [
+            at(self.left, self.top),
+            at(self.right, self.top),
+            at(self.right, self.bottom),
+            at(self.left, self.bottom),
+        ]
+    }
+}
+
+/// Unit vectors along and perpendicular to a line at `angle`, with the normal pointing down
+fn axes(angle: f32) -> (Point<f32>, Point<f32>) {
+    let (sin, cos) = angle.sin_cos();
+    (Point::new(cos, sin), Point::new(-sin, cos))
+}
+
+fn dot(a: Point<f32>, b: Point<f32>) -> f32 {
+    a.x * b.x + a.y * b.y
+}
+
+fn angle_diff(a: f32, b: f32) -> f32 {
+    let diff = (a - b).rem_euclid(std::f32::consts::TAU);
+    diff.min(std::f32::consts::TAU - diff)
+}
+
+fn median(mut values: Vec<f32>) -> f32 {
+    values.sort_by(f32::total_cmp);
+    values[values.len() / 2]
+}
+
+/// Direction in which text is read
+#[derive(Config, Debug, Copy, PartialEq, Eq)]
+pub enum ReadingDirection {
+    /// Horizontal lines read left to right, e.g. Latin scripts
+    LeftToRight,
+    /// Horizontal lines read right to left, e.g. Arabic or Hebrew
+    RightToLeft,
+    /// Vertical lines read top to bottom, with lines ordered right to left, e.g. vertical CJK
+    VerticalRightToLeft,
+}
+
+impl ReadingDirection {
+    /// Guess the reading direction from the text direction of each detection, as estimated by
+    /// [`text_directions`](crate::utils::characters::text_directions). Pages where most detections
+    /// run closer to vertical than horizontal are read as vertical CJK, and all others left to
+    /// right, since right to left scripts can't be told apart by geometry.
+    pub fn from_text_directions<'a>(
+        directions: impl IntoIterator<Item = &'a TextDirection>,
+    ) -> Self {
+        let (mut vertical, mut total) = (0, 0);
+        for direction in directions {
+            total += 1;
+            if direction.is_vertical() {
+                vertical += 1;
+            }
+        }
+        if 2 * vertical > total {
+            ReadingDirection::VerticalRightToLeft
+        } else {
+            ReadingDirection::LeftToRight
+        }
+    }
+}
+
+#[derive(Config, Debug)]
+pub struct ReadingOrderConfig {
+    #[config(default = "ReadingDirection::LeftToRight")]
+    direction: ReadingDirection,
+    /// Minimum whitespace between columns, relative to the median line height. Smaller gaps are
+    /// treated as spaces between words.
+    #[config(default = 1.0)]
+    column_gap: f32,
+}
+
+/// Sort boxes in reading order with a recursive XY-cut. Whitespace gaps in the projection of the
+/// boxes first split the page into columns, then each column is split into blocks from top to
+/// bottom, recursively. Returns the detection ids in reading order.
+pub fn reading_order(
+    boxes: &HashMap<u32, [Point<f32>; 4]>,
+    config: &ReadingOrderConfig,
+) -> Vec<u32> {
+    let mut regions: Vec<_> = boxes
+        .iter()
+        .map(|(id, bbox)| Region::new(*id, bbox, config.direction))
+        .collect();
+    if regions.is_empty() {
+        return Vec::new();
+    }
+    // Start from a deterministic order, since the map has none
+    regions.sort_by_key(|r| r.id);
+
+    let line_height = median(regions.iter().map(|r| r.bottom - r.top).collect());
+    let mut order = Vec::with_capacity(regions.len());
+    xy_cut(&mut regions, config.column_gap * line_height, &mut order);
+    order
+}
+
+/// A box in a frame where lines run along increasing `left` to `right`, and successive lines are
+/// stacked along increasing `top` to `bottom`, regardless of the reading direction
+struct Region {
+    id: u32,
+    left: f32,
+    right: f32,
+    top: f32,
+    bottom: f32,
+}
+
+impl Region {
+    fn new(id: u32, bbox: &[Point<f32>; 4], direction: ReadingDirection) -> Self {
+        let to_frame = |p: &Point<f32>| match direction {
+            ReadingDirection::LeftToRight => (p.x, p.y),
+            ReadingDirection::RightToLeft => (-p.x, p.y),
+            ReadingDirection::VerticalRightToLeft => (p.y, -p.x),
+        };
+        let mut region = Region {
+            id,
+            left: f32::MAX,
+            right: f32::MIN,
+            top: f32::MAX,
+            bottom: f32::MIN,
+        };
+        for (u, v) in bbox.iter().map(to_frame) {
+            region.left = region.left.min(u);
+            region.right = region.right.max(u);
+            region.top = region.top.min(v);
+            region.bottom = region.bottom.max(v);
+        }
+        region
+    }
+
+    fn center(&self) -> (f32, f32) {
+        (
+            (self.left + self.right) / 2.0,
+            (self.top + self.bottom) / 2.0,
+        )
+    }
+}
+
+fn xy_cut(regions: &mut [Region], column_gap: f32, order: &mut Vec<u32>) {
+    if regions.len() <= 1 {
+        order.extend(regions.iter().map(|r| r.id));
+        return;
+    }
+
+    let column = |r: &Region| (r.left, r.right);
+    let row = |r: &Region| (r.top, r.bottom);
+
+    let cuts = find_cuts(regions, column, column_gap);
+    if !cuts.is_empty() {
+        for column in split_at(regions, &cuts) {
+            xy_cut(column, column_gap, order);
+        }
+        return;
+    }
+
+    // A header spanning all columns prevents a column cut, so split into rows instead. Rows that
+    // still form columns together are kept together, so that paragraph breaks lining up across
+    // columns do not make the columns be read row by row.
+    let mut cuts = find_cuts(regions, row, 0.0);
+    let mut start = 0;
+    cuts.push(regions.len());
+    let mut kept = Vec::new();
+    for i in 0..cuts.len() - 1 {
+        let merged = &regions[start..cuts[i + 1]];
+        if !(has_cuts(&regions[start..cuts[i]], column, column_gap)
+            && has_cuts(merged, column, column_gap))
+        {
+            kept.push(cuts[i]);
+            start = cuts[i];
+        }
+    }
+    if !kept.is_empty() {
+        for block in split_at(regions, &kept) {
+            xy_cut(block, column_gap, order);
+        }
+        return;
+    }
+
+    // No whitespace left to cut at, fall back to sorting by lines
+    order.extend(sort_lines(regions));
+}
+
+/// Sort `regions` by their interval along one axis, and find the indices at which there is a gap
+/// of more than `min_gap` to all previous intervals
+fn find_cuts(
+    regions: &mut [Region],
+    interval: impl Fn(&Region) -> (f32, f32),
+    min_gap: f32,
+) -> Vec<usize> {
+    regions.sort_by(|a, b| interval(a).0.total_cmp(&interval(b).0));
+    let mut cuts = Vec::new();
+    let mut end = f32::MIN;
+    for (i, region) in regions.iter().enumerate() {
+        let (start, stop) = interval(region);
+        if i > 0 && start - end > min_gap {
+            cuts.push(i);
+        }
+        end = end.max(stop);
+    }
+    cuts
+}
+
+fn has_cuts(regions: &[Region], interval: impl Fn(&Region) -> (f32, f32), min_gap: f32) -> bool {
+    let mut intervals: Vec<_> = regions.iter().map(interval).collect();
+    intervals.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut end = f32::MIN;
+    intervals.iter().enumerate().any(|(i, (start, stop))| {
+        let cut = i > 0 && start - end > min_gap;
+        end = end.max(*stop);
+        cut
+    })
+}
+
+fn split_at<'a>(regions: &'a mut [Region], cuts: &[usize]) -> Vec<&'a mut [Region]> {
+    let mut parts = Vec::with_capacity(cuts.len() + 1);
+    let mut rest = regions;
+    let mut offset = 0;
+    for cut in cuts {
+        let (part, tail) = rest.split_at_mut(cut - offset);
+        parts.push(part);
+        rest = tail;
+        offset = *cut;
+    }
+    parts.push(rest);
+    parts
+}
+
+/// Order regions that overlap each other line by line. A region joins the current line if its
+/// center lies within the vertical extent of the line's first region.
+fn sort_lines(regions: &mut [Region]) -> Vec<u32> {
+    regions.sort_by(|a, b| a.center().1.total_cmp(&b.center().1));
+    let mut order = Vec::with_capacity(regions.len());
+    let mut start = 0;
+    while start < regions.len() {
+        let (top, bottom) = (regions[start].top, regions[start].bottom);
+        let end = start
+            + regions[start..]
+                .iter()
+                .take_while(|r| (top..=bottom).contains(&r.center().1))
+                .count()
+                .max(1);
+        let line = &mut regions[start..end];
+        line.sort_by(|a, b| a.center().0.total_cmp(&b.center().0));
+        order.extend(line.iter().map(|r| r.id));
+        start = end;
+    }
+    order
+}
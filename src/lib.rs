@@ -12,8 +12,18 @@ macro_rules! sequential {
 
 mod craft;
 
+pub mod crop;
+pub mod deskew;
+pub mod geometry;
+pub mod heatmap;
 pub mod image_util;
+pub mod layout;
+pub mod nms;
+pub mod npy;
+pub mod orientation;
 pub mod refine;
+pub mod regions;
+pub mod visualize;
 pub use craft::*;
 
 #[cfg(feature = "import")]
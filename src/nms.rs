@@ -0,0 +1,263 @@
+use imageproc::point::Point;
+
+use crate::{
+    crop::normalize_quad,
+    geometry::{area, distance, intersection_area, iou},
+};
+
+/// How soft-NMS decays the score of a box overlapping a higher scoring one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SoftNmsDecay {
+    /// Multiply by `1 - iou` if the IoU is above the threshold
+    Linear,
+    /// Multiply by `exp(-iou^2 / sigma)`, regardless of the threshold
+    Gaussian { sigma: f32 },
+}
+
+/// Standard non-maximum suppression. Returns the indices of the kept boxes, from highest to lowest
+/// score. A box is suppressed if its IoU with a kept box is above `iou_threshold`.
+pub fn nms<Q: AsRef<[Point<f32>]>>(boxes: &[Q], scores: &[f32], iou_threshold: f32) -> Vec<usize> {
+    let shapes = Shapes::new(boxes);
+    let mut kept: Vec<usize> = Vec::new();
+    for i in by_score(scores) {
+        if kept.iter().all(|k| shapes.iou(i, *k) <= iou_threshold) {
+            kept.push(i);
+        }
+    }
+    kept
+}
+
+/// Soft non-maximum suppression. Instead of removing overlapping boxes, their scores are decayed
+/// by their overlap with higher scoring boxes. Returns the indices and decayed scores of the boxes
+/// that stay at or above `score_threshold`, from highest to lowest decayed score.
+pub fn soft_nms<Q: AsRef<[Point<f32>]>>(
+    boxes: &[Q],
+    scores: &[f32],
+    iou_threshold: f32,
+    decay: SoftNmsDecay,
+    score_threshold: f32,
+) -> Vec<(usize, f32)> {
+    let shapes = Shapes::new(boxes);
+    let mut remaining: Vec<(usize, f32)> = scores.iter().copied().enumerate().collect();
+    let mut kept = Vec::new();
+    while !remaining.is_empty() {
+        let best = (0..remaining.len())
+            .max_by(|a, b| remaining[*a].1.total_cmp(&remaining[*b].1))
+            .unwrap();
+        let (i, score) = remaining.swap_remove(best);
+        if score < score_threshold {
+            break;
+        }
+        kept.push((i, score));
+
+        for (j, score) in remaining.iter_mut() {
+            let iou = shapes.iou(i, *j);
+            *score *= match decay {
+                SoftNmsDecay::Linear if iou > iou_threshold => 1.0 - iou,
+                SoftNmsDecay::Linear => 1.0,
+                SoftNmsDecay::Gaussian { sigma } => (-iou * iou / sigma).exp(),
+            };
+        }
+    }
+    kept
+}
+
+/// Weighted box fusion. Boxes are clustered greedily from highest to lowest score, joining the
+/// first cluster whose fused box they overlap with an IoU above `iou_threshold`. Each cluster is
+/// fused into a box whose points are the score weighted average of its members' points, with
+/// the mean score of its members. Returns the fused boxes and scores, from highest to lowest
+/// member score.
+///
+/// Quads may have their corners in any order. Longer polygons hold `n` points along the top from
+/// left to right, followed by `n` points along the bottom from right to left, like in
+/// [`crop_polygon`](crate::crop::crop_polygon). Points only correspond between boxes with the same
+/// number of points, so every box is resampled by arc length to as many points along its top and
+/// bottom as the longest polygon. Fused boxes are quads if all boxes are.
+///
+/// # Panics
+/// If a box has fewer than 4 points, or an odd number of points.
+pub fn weighted_box_fusion<Q: AsRef<[Point<f32>]>>(
+    boxes: &[Q],
+    scores: &[f32],
+    iou_threshold: f32,
+) -> Vec<(Vec<Point<f32>>, f32)> {
+    struct Cluster {
+        fused: Vec<Point<f32>>,
+        /// Score weighted sum of the points
+        sum: Vec<Point<f32>>,
+        total_score: f32,
+        count: usize,
+    }
+
+    for bbox in boxes {
+        let len = bbox.as_ref().len();
+        assert!(
+            len >= 4 && len.is_multiple_of(2),
+            "Polygon must have an even number of at least 4 points"
+        );
+    }
+    let side = boxes
+        .iter()
+        .map(|b| b.as_ref().len() / 2)
+        .max()
+        .unwrap_or(2);
+
+    let mut clusters: Vec<Cluster> = Vec::new();
+    for i in by_score(scores) {
+        // Points must correspond to each other to be averaged
+        let polygon = match boxes[i].as_ref() {
+            &[a, b, c, d] => normalize_quad([a, b, c, d], false).to_vec(),
+            polygon => polygon.to_vec(),
+        };
+        let polygon = resample_polygon(&polygon, side);
+        let score = scores[i];
+        let weighted: Vec<_> = polygon
+            .iter()
+            .map(|p| Point::new(p.x * score, p.y * score))
+            .collect();
+
+        let target = clusters
+            .iter()
+            .position(|cluster| iou(&cluster.fused, &polygon) > iou_threshold);
+        match target {
+            Some(c) => {
+                let cluster = &mut clusters[c];
+                for (sum, p) in cluster.sum.iter_mut().zip(weighted) {
+                    *sum += p;
+                }
+                cluster.total_score += score;
+                cluster.count += 1;
+                let total = cluster.total_score.max(1e-6);
+                cluster.fused = cluster
+                    .sum
+                    .iter()
+                    .map(|p| Point::new(p.x / total, p.y / total))
+                    .collect();
+            }
+            None => clusters.push(Cluster {
+                fused: polygon,
+                sum: weighted,
+                total_score: score,
+                count: 1,
+            }),
+        }
+    }
+
+    clusters
+        .into_iter()
+        .map(|c| (c.fused, c.total_score / c.count as f32))
+        .collect()
+}
+
+/// Resample the top and bottom boundary of a polygon laid out like in [`weighted_box_fusion`] to
+/// `side` points each, evenly spaced by arc length and keeping their end points
+fn resample_polygon(polygon: &[Point<f32>], side: usize) -> Vec<Point<f32>> {
+    let n = polygon.len() / 2;
+    if n == side {
+        return polygon.to_vec();
+    }
+    let (top, bottom) = polygon.split_at(n);
+    let mut out = resample_line(top, side);
+    out.extend(resample_line(bottom, side));
+    out
+}
+
+/// `count` points evenly spaced by arc length along the polyline `line`, from its first to its
+/// last point
+fn resample_line(line: &[Point<f32>], count: usize) -> Vec<Point<f32>> {
+    let mut lengths = vec![0.0];
+    for pair in line.windows(2) {
+        lengths.push(lengths[lengths.len() - 1] + distance(pair[0], pair[1]));
+    }
+    let total = lengths[lengths.len() - 1];
+
+    (0..count)
+        .map(|k| {
+            let target = total * k as f32 / (count - 1) as f32;
+            let i = lengths[1..line.len() - 1].partition_point(|l| *l < target);
+            let segment = (lengths[i + 1] - lengths[i]).max(1e-6);
+            let t = ((target - lengths[i]) / segment).clamp(0.0, 1.0);
+            let (a, b) = (line[i], line[i + 1]);
+            Point::new(a.x + (b.x - a.x) * t, a.y + (b.y - a.y) * t)
+        })
+        .collect()
+}
+
+/// Remove fragments that lie inside a larger box. A box is absorbed if at least `containment` of
+/// its area lies inside a larger box with at least the same score, so a confident fragment is not
+/// swallowed by a doubtful container. Returns the indices of the kept boxes in their original
+/// order.
+pub fn absorb_contained<Q: AsRef<[Point<f32>]>>(
+    boxes: &[Q],
+    scores: &[f32],
+    containment: f32,
+) -> Vec<usize> {
+    let shapes = Shapes::new(boxes);
+    (0..boxes.len())
+        .filter(|i| {
+            let absorbed = (0..boxes.len()).any(|j| {
+                *i != j
+                    && shapes.areas[j] > shapes.areas[*i]
+                    && scores[j] >= scores[*i]
+                    && shapes.intersection(*i, j) >= containment * shapes.areas[*i]
+            });
+            !absorbed
+        })
+        .collect()
+}
+
+/// Indices sorted by decreasing score
+fn by_score(scores: &[f32]) -> Vec<usize> {
+    let mut order: Vec<_> = (0..scores.len()).collect();
+    order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+    order
+}
+
+/// Polygons with their areas and axis aligned bounds, to skip pairs that can't overlap
+struct Shapes<'a, Q> {
+    polygons: &'a [Q],
+    areas: Vec<f32>,
+    bounds: Vec<[f32; 4]>,
+}
+
+impl<'a, Q: AsRef<[Point<f32>]>> Shapes<'a, Q> {
+    fn new(polygons: &'a [Q]) -> Self {
+        let areas = polygons.iter().map(|p| area(p.as_ref())).collect();
+        let bounds = polygons
+            .iter()
+            .map(|p| {
+                let p = p.as_ref();
+                let (xs, ys) = (p.iter().map(|p| p.x), p.iter().map(|p| p.y));
+                [
+                    xs.clone().fold(f32::MAX, f32::min),
+                    ys.clone().fold(f32::MAX, f32::min),
+                    xs.fold(f32::MIN, f32::max),
+                    ys.fold(f32::MIN, f32::max),
+                ]
+            })
+            .collect();
+        Self {
+            polygons,
+            areas,
+            bounds,
+        }
+    }
+
+    fn intersection(&self, i: usize, j: usize) -> f32 {
+        let ([l0, t0, r0, b0], [l1, t1, r1, b1]) = (self.bounds[i], self.bounds[j]);
+        if l0 > r1 || l1 > r0 || t0 > b1 || t1 > b0 {
+            return 0.0;
+        }
+        intersection_area(self.polygons[i].as_ref(), self.polygons[j].as_ref())
+    }
+
+    fn iou(&self, i: usize, j: usize) -> f32 {
+        let intersection = self.intersection(i, j);
+        let union = self.areas[i] + self.areas[j] - intersection;
+        if union <= 0.0 {
+            0.0
+        } else {
+            intersection / union
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    path::Path,
+};
+
+use burn::{
+    prelude::Backend,
+    tensor::{Tensor, TensorData},
+};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// A C order `f32` array, as stored in NPY files
+#[derive(Clone, Debug, PartialEq)]
+pub struct NpyArray {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+impl NpyArray {
+    pub fn new(shape: Vec<usize>, data: Vec<f32>) -> Self {
+        assert_eq!(
+            shape.iter().product::<usize>(),
+            data.len(),
+            "Shape must match the data"
+        );
+        Self { shape, data }
+    }
+
+    /// Read back a tensor
+    pub fn from_tensor<B: Backend, const D: usize>(tensor: Tensor<B, D>) -> Self {
+        let shape = tensor.dims().to_vec();
+        let data = tensor.into_data().convert::<f32>().to_vec::<f32>().unwrap();
+        Self::new(shape, data)
+    }
+
+    /// Upload as a tensor. Panics if the array doesn't have `D` dimensions.
+    pub fn to_tensor<B: Backend, const D: usize>(&self, device: &B::Device) -> Tensor<B, D> {
+        assert_eq!(self.shape.len(), D, "Array must have {D} dimensions");
+        Tensor::from_data(
+            TensorData::new(self.data.clone(), self.shape.clone()),
+            device,
+        )
+    }
+}
+
+/// Write a little endian `f32` array with the given shape in the NPY format, version 1.0
+pub fn write_npy<W: Write>(mut writer: W, shape: &[usize], data: &[f32]) -> io::Result<()> {
+    assert_eq!(
+        shape.iter().product::<usize>(),
+        data.len(),
+        "Shape must match the data"
+    );
+
+    let dims: Vec<_> = shape.iter().map(|d| d.to_string()).collect();
+    let shape = match dims.as_slice() {
+        [d] => format!("({d},)"),
+        dims => format!("({})", dims.join(", ")),
+    };
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {shape}, }}");
+    // Magic, version and header length take 10 bytes, and the data must start 64 byte aligned
+    let unpadded = 10 + header.len() + 1;
+    header.push_str(&" ".repeat(unpadded.next_multiple_of(64) - unpadded));
+    header.push('\n');
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for value in data {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Read a little endian, C order floating point array in the NPY format. Half and double
+/// precision are converted to `f32`.
+pub fn read_npy<R: Read>(mut reader: R) -> io::Result<NpyArray> {
+    let mut preamble = [0; 8];
+    reader.read_exact(&mut preamble)?;
+    if &preamble[..6] != MAGIC {
+        return Err(invalid("Not an NPY file"));
+    }
+    // Version 1 stores the header length in 2 bytes, later versions in 4
+    let header_len = if preamble[6] == 1 {
+        let mut len = [0; 2];
+        reader.read_exact(&mut len)?;
+        u16::from_le_bytes(len) as usize
+    } else {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        u32::from_le_bytes(len) as usize
+    };
+    let mut header = vec![0; header_len];
+    reader.read_exact(&mut header)?;
+    let header = String::from_utf8_lossy(&header);
+
+    if header_value(&header, "fortran_order") != Some("False") {
+        return Err(invalid("Fortran order arrays are not supported"));
+    }
+    let shape: Vec<usize> = header_value(&header, "shape")
+        .ok_or_else(|| invalid("Missing shape"))?
+        .trim_matches(['(', ')'])
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse().map_err(|_| invalid("Invalid shape")))
+        .collect::<io::Result<_>>()?;
+    let size: usize = shape.iter().product();
+
+    let descr = header_value(&header, "descr").ok_or_else(|| invalid("Missing dtype"))?;
+    let (bytes, convert): (usize, fn(&[u8]) -> f32) = match descr.trim_matches(['\'', '"']) {
+        "<f4" => (4, |b| f32::from_le_bytes(b.try_into().unwrap())),
+        "<f8" => (8, |b| f64::from_le_bytes(b.try_into().unwrap()) as f32),
+        "<f2" => (2, |b| {
+            half::f16::from_le_bytes(b.try_into().unwrap()).to_f32()
+        }),
+        _ => return Err(invalid(&format!("Unsupported dtype {descr}"))),
+    };
+    let mut raw = vec![0; size * bytes];
+    reader.read_exact(&mut raw)?;
+    let data = raw.chunks_exact(bytes).map(convert).collect();
+    Ok(NpyArray { shape, data })
+}
+
+/// Write named arrays to an NPZ archive, like `numpy.savez_compressed`
+pub fn write_npz<W: Write + Seek>(writer: W, arrays: &[(&str, &NpyArray)]) -> io::Result<()> {
+    let mut zip = ZipWriter::new(writer);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, array) in arrays {
+        zip.start_file(format!("{name}.npy"), options)?;
+        write_npy(&mut zip, &array.shape, &array.data)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Read all arrays of an NPZ archive, by their name without the `.npy` extension
+pub fn read_npz<R: Read + Seek>(reader: R) -> io::Result<HashMap<String, NpyArray>> {
+    let mut zip = ZipArchive::new(reader)?;
+    let mut arrays = HashMap::new();
+    for i in 0..zip.len() {
+        let file = zip.by_index(i)?;
+        let name = file.name().trim_end_matches(".npy").to_string();
+        arrays.insert(name, read_npy(file)?);
+    }
+    Ok(arrays)
+}
+
+/// Save a tensor to an NPY file
+pub fn save_npy<B: Backend, const D: usize>(
+    path: impl AsRef<Path>,
+    tensor: Tensor<B, D>,
+) -> io::Result<()> {
+    let array = NpyArray::from_tensor(tensor);
+    write_npy(
+        BufWriter::new(File::create(path)?),
+        &array.shape,
+        &array.data,
+    )
+}
+
+/// Load an NPY file
+pub fn load_npy(path: impl AsRef<Path>) -> io::Result<NpyArray> {
+    read_npy(BufReader::new(File::open(path)?))
+}
+
+/// Save named tensors to an NPZ file, e.g. the outputs of [`Craft::forward`](crate::Craft) and
+/// [`RefineNet::forward`](crate::refine::RefineNet) to replay the post-processing later
+pub fn save_npz<B: Backend, const D: usize>(
+    path: impl AsRef<Path>,
+    tensors: Vec<(&str, Tensor<B, D>)>,
+) -> io::Result<()> {
+    let arrays: Vec<_> = tensors
+        .into_iter()
+        .map(|(name, tensor)| (name, NpyArray::from_tensor(tensor)))
+        .collect();
+    let arrays: Vec<_> = arrays.iter().map(|(name, array)| (*name, array)).collect();
+    write_npz(BufWriter::new(File::create(path)?), &arrays)
+}
+
+/// Load all arrays of an NPZ file
+pub fn load_npz(path: impl AsRef<Path>) -> io::Result<HashMap<String, NpyArray>> {
+    read_npz(BufReader::new(File::open(path)?))
+}
+
+/// Value of `key` in the dictionary literal of an NPY header
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{key}'"))? + key.len() + 2;
+    let rest = header[start..].trim_start().strip_prefix(':')?.trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')')? + 1
+    } else {
+        rest.find([',', '}'])?
+    };
+    Some(rest[..end].trim())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use burn::backend::NdArray;
+
+    use super::*;
+
+    fn array() -> NpyArray {
+        NpyArray::new(vec![2, 3], vec![0.0, 0.25, -1.5, 3.0, f32::MAX, 1e-8])
+    }
+
+    /// NPY file with a version 2.0 header, in the given dtype
+    fn npy_v2(descr: &str, shape: &str, data: &[u8]) -> Vec<u8> {
+        let header =
+            format!("{{'descr': '{descr}', 'fortran_order': False, 'shape': {shape}, }}\n");
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([2, 0]);
+        bytes.extend((header.len() as u32).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn npy_round_trip() {
+        let mut bytes = Vec::new();
+        write_npy(&mut bytes, &array().shape, &array().data).unwrap();
+        // Data starts 64 byte aligned
+        assert_eq!((bytes.len() - 6 * 4) % 64, 0);
+        assert_eq!(read_npy(&bytes[..]).unwrap(), array());
+
+        let mut bytes = Vec::new();
+        write_npy(&mut bytes, &[4], &[1.0, 2.0, 3.0, 4.0]).unwrap();
+        assert_eq!(read_npy(&bytes[..]).unwrap().shape, vec![4]);
+    }
+
+    #[test]
+    fn npz_round_trip() {
+        let scalar = NpyArray::new(vec![], vec![7.0]);
+        let mut bytes = Cursor::new(Vec::new());
+        write_npz(&mut bytes, &[("a", &array()), ("b", &scalar)]).unwrap();
+
+        let arrays = read_npz(Cursor::new(bytes.into_inner())).unwrap();
+        assert_eq!(arrays.len(), 2);
+        assert_eq!(arrays["a"], array());
+        assert_eq!(arrays["b"], scalar);
+    }
+
+    #[test]
+    fn tensor_round_trip() {
+        let device = Default::default();
+        let tensor: Tensor<NdArray, 2> = array().to_tensor(&device);
+        assert_eq!(NpyArray::from_tensor(tensor), array());
+    }
+
+    #[test]
+    fn read_other_dtypes() {
+        let f64s: Vec<u8> = [1.5f64, -2.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let array = read_npy(&npy_v2("<f8", "(2,)", &f64s)[..]).unwrap();
+        assert_eq!(array, NpyArray::new(vec![2], vec![1.5, -2.0]));
+
+        let f16s: Vec<u8> = [0.5f32, 4.0]
+            .iter()
+            .flat_map(|v| half::f16::from_f32(*v).to_le_bytes())
+            .collect();
+        let array = read_npy(&npy_v2("<f2", "(1, 2)", &f16s)[..]).unwrap();
+        assert_eq!(array, NpyArray::new(vec![1, 2], vec![0.5, 4.0]));
+    }
+
+    #[test]
+    fn reject_unsupported() {
+        assert!(read_npy(&npy_v2("<i4", "(1,)", &[0; 4])[..]).is_err());
+        assert!(read_npy(&npy_v2("<f4", "(2,)", &[0; 4])[..]).is_err());
+        assert!(read_npy(&b"not an npy file"[..]).is_err());
+
+        let fortran = npy_v2("<f4", "(1,)", &[0; 4]);
+        let fortran = String::from_utf8_lossy(&fortran).replace("False", "True ");
+        assert!(read_npy(fortran.as_bytes()).is_err());
+    }
+}
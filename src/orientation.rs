@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use burn::{config::Config, prelude::Backend, tensor::Tensor};
+use image::{DynamicImage, GenericImageView};
+use imageproc::point::Point;
+
+use crate::{
+    crop::normalize_quad, geometry::distance, image_util::Rotation,
+    utils::characters::TextDirection,
+};
+
+#[derive(Config, Debug)]
+pub struct OrientationConfig {
+    /// Run detection on the page rotated by 180 degrees as well, and keep the orientation with the
+    /// highest confidence. Box geometry alone can't tell a page from its upside down version.
+    #[config(default = true)]
+    compare_rotations: bool,
+    /// Minimum ratio between the long and short side of a box for it to count towards the
+    /// orientation. Boxes closer to square, like single characters, have no clear direction.
+    #[config(default = 1.5)]
+    min_elongation: f32,
+}
+
+/// Output of a detection run on one orientation of a page
+pub struct PageDetections {
+    /// Boxes in the coordinates of the image detection was run on
+    pub boxes: HashMap<u32, [Point<f32>; 4]>,
+    /// Text direction per box in the same coordinates, e.g. from
+    /// [`text_directions`](crate::utils::characters::text_directions). May be empty or only cover
+    /// some of the boxes.
+    pub directions: HashMap<u32, TextDirection>,
+    /// Overall confidence, e.g. from [`heatmap_confidence`]
+    pub confidence: f32,
+}
+
+pub struct OrientationResult {
+    /// The page rotated upright
+    pub image: DynamicImage,
+    /// Clockwise rotation that was applied to the original image
+    pub rotation: Rotation,
+    /// Detections on the upright page, mapped back to the coordinates of the original image
+    pub boxes: HashMap<u32, [Point<f32>; 4]>,
+    pub confidence: f32,
+}
+
+/// Estimate the rotation that makes a page upright from its detections. Boxes vote for the
+/// orientation of their long side, using the text direction where one is known, weighted by their
+/// length. The result is either [`Rotation::Rotate0`] or [`Rotation::Rotate90`], since the
+/// direction of the text along its axis is unknown.
+///
+/// Vertical text on an upright page, like vertical CJK, is indistinguishable from a sideways page.
+pub fn estimate_rotation(
+    boxes: &HashMap<u32, [Point<f32>; 4]>,
+    directions: &HashMap<u32, TextDirection>,
+    min_elongation: f32,
+) -> Rotation {
+    let (mut upright, mut sideways) = (0.0, 0.0);
+    for (id, bbox) in boxes {
+        let [a, b, c, d] = normalize_quad(*bbox, false);
+        let width = (distance(a, b) + distance(d, c)) / 2.0;
+        let height = (distance(a, d) + distance(b, c)) / 2.0;
+        if width.max(height) < min_elongation * width.min(height) {
+            continue;
+        }
+
+        let is_sideways = match directions.get(id) {
+            Some(direction) => direction.is_vertical(),
+            None => height > width,
+        };
+        if is_sideways {
+            sideways += width.max(height);
+        } else {
+            upright += width.max(height);
+        }
+    }
+
+    if sideways > upright {
+        Rotation::Rotate90
+    } else {
+        Rotation::Rotate0
+    }
+}
+
+/// Mean region score over the pixels of `text_map` of at least `low_text`, as a measure of how
+/// confident the network is about the text on a page
+pub fn heatmap_confidence<B: Backend>(text_map: Tensor<B, 4>, low_text: f64) -> f32 {
+    let mask = text_map.clone().greater_equal_elem(low_text).float();
+    let sums = Tensor::cat(vec![(text_map * mask.clone()).sum(), mask.sum()], 0);
+    let sums = sums.into_data().convert::<f32>().to_vec::<f32>().unwrap();
+    sums[0] / sums[1].max(1.0)
+}
+
+/// Detect the orientation of a page and rotate it upright. `detect` runs the full detection on an
+/// image, and is called on the original image and on the rotated candidates left after
+/// [`estimate_rotation`]: the estimate alone, or with `compare_rotations` the estimate and its
+/// upside down version. Candidates other than the original image are detected on again, so the
+/// boxes always come from the upright page.
+pub fn correct_orientation(
+    image: &DynamicImage,
+    config: &OrientationConfig,
+    mut detect: impl FnMut(&DynamicImage) -> PageDetections,
+) -> OrientationResult {
+    let original = detect(image);
+    let estimate = estimate_rotation(&original.boxes, &original.directions, config.min_elongation);
+
+    // The estimate only decides the axis of the text, so compare it with its upside down version
+    let mut candidates = vec![estimate];
+    if config.compare_rotations {
+        candidates.push(estimate.then(Rotation::Rotate180));
+    }
+
+    let mut original = Some(original);
+    let mut best: Option<(Rotation, DynamicImage, PageDetections)> = None;
+    for rotation in candidates {
+        let rotated = rotation.apply(image);
+        let detections = match rotation {
+            Rotation::Rotate0 => original.take().unwrap(),
+            _ => detect(&rotated),
+        };
+        if best
+            .as_ref()
+            .is_none_or(|(_, _, best)| detections.confidence > best.confidence)
+        {
+            best = Some((rotation, rotated, detections));
+        }
+    }
+
+    let (rotation, rotated, detections) = best.unwrap();
+    let rotated_size = rotated.dimensions();
+    let mut boxes = detections.boxes;
+    for point in boxes.values_mut().flatten() {
+        *point = rotation.inverse().rotate_point(*point, rotated_size);
+    }
+
+    OrientationResult {
+        image: rotated,
+        rotation,
+        boxes,
+        confidence: detections.confidence,
+    }
+}
@@ -0,0 +1,149 @@
+use burn::{
+    prelude::Backend,
+    tensor::{Tensor, TensorData},
+};
+use image::{DynamicImage, GrayImage};
+use imageproc::{point::Point, rect::Rect};
+use rayon::prelude::*;
+
+use crate::{
+    geometry::contains,
+    image_util::{ImageTransform, Preprocess, ResizeResult},
+};
+
+/// Zones of an image to detect text in and areas to ignore, in original image coordinates
+#[derive(Clone, Debug, Default)]
+pub struct DetectionRegions {
+    /// Polygons to detect text in. Without any, the whole image is used.
+    pub include: Vec<Vec<Point<f32>>>,
+    /// Pixels to ignore are non-zero. Must have the size of the image.
+    pub exclude: Option<GrayImage>,
+}
+
+impl DetectionRegions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Detect text inside `rect`, including its border pixels
+    pub fn with_rect(self, rect: Rect) -> Self {
+        let (left, top) = (rect.left() as f32 - 0.5, rect.top() as f32 - 0.5);
+        let (right, bottom) = (rect.right() as f32 + 0.5, rect.bottom() as f32 + 0.5);
+        self.with_polygon(vec![
+            Point::new(left, top),
+            Point::new(right, top),
+            Point::new(right, bottom),
+            Point::new(left, bottom),
+        ])
+    }
+
+    /// Detect text inside `polygon`
+    pub fn with_polygon(mut self, polygon: Vec<Point<f32>>) -> Self {
+        self.include.push(polygon);
+        self
+    }
+
+    /// Ignore the non-zero pixels of `mask`
+    pub fn with_exclusion_mask(mut self, mask: GrayImage) -> Self {
+        self.exclude = Some(mask);
+        self
+    }
+
+    /// Bounding box of the included regions, clipped to an image of `image_size`. The whole image
+    /// if there are no included regions.
+    pub fn crop(&self, (width, height): (u32, u32)) -> Rect {
+        if self.include.is_empty() {
+            return Rect::at(0, 0).of_size(width, height);
+        }
+
+        let max_x = width.saturating_sub(1) as f32;
+        let max_y = height.saturating_sub(1) as f32;
+        let (mut left, mut top, mut right, mut bottom) = (max_x, max_y, 0.0f32, 0.0f32);
+        // Pixels containing the points, where points on a pixel border count towards the inner pixel
+        for p in self.include.iter().flatten() {
+            left = left.min((p.x + 0.5).floor().max(0.0));
+            top = top.min((p.y + 0.5).floor().max(0.0));
+            right = right.max((p.x - 0.5).ceil().min(max_x));
+            bottom = bottom.max((p.y - 0.5).ceil().min(max_y));
+        }
+        // Regions outside the image still need a valid crop, everything in it gets masked
+        let right = right.max(left);
+        let bottom = bottom.max(top);
+        Rect::at(left as i32, top as i32)
+            .of_size((right - left) as u32 + 1, (bottom - top) as u32 + 1)
+    }
+
+    /// Preprocess only the bounding crop of the included regions. The transform of the result maps
+    /// back to the full image.
+    pub fn preprocess<B: Backend>(
+        &self,
+        image: &DynamicImage,
+        preprocess: &Preprocess<B>,
+    ) -> ResizeResult<B> {
+        let size = (image.width(), image.height());
+        let crop = self.crop(size);
+        if (crop.width(), crop.height()) == size {
+            return preprocess.forward(image);
+        }
+
+        let cropped = image.crop_imm(
+            crop.left() as u32,
+            crop.top() as u32,
+            crop.width(),
+            crop.height(),
+        );
+        let mut result = preprocess.forward(&cropped);
+        result.transform = result.transform.with_crop(crop, size);
+        result
+    }
+
+    /// Mask of the heatmap pixels whose center lies in an included region and not on an excluded
+    /// pixel, as a `[1, height, width, 1]` tensor of ones and zeros
+    pub fn heatmap_mask<B: Backend>(
+        &self,
+        transform: &ImageTransform,
+        (height, width): (usize, usize),
+        device: &B::Device,
+    ) -> Tensor<B, 4> {
+        if let Some(exclude) = &self.exclude {
+            assert_eq!(
+                exclude.dimensions(),
+                transform.image_size,
+                "Exclusion mask must have the size of the image"
+            );
+        }
+
+        let mask: Vec<f32> = (0..height * width)
+            .into_par_iter()
+            .map(|i| {
+                let p = Point::new((i % width) as f32, (i / width) as f32);
+                let p = transform.to_image(p);
+                let included =
+                    self.include.is_empty() || self.include.iter().any(|poly| contains(poly, p));
+                let excluded = self.exclude.as_ref().is_some_and(|exclude| {
+                    let p = transform.clip(p);
+                    exclude.get_pixel(p.x.round() as u32, p.y.round() as u32)[0] != 0
+                });
+                (included && !excluded) as u8 as f32
+            })
+            .collect();
+        Tensor::from_data(TensorData::new(mask, [1, height, width, 1]), device)
+    }
+
+    /// Zero out the scores outside the included regions and on excluded pixels, before passing
+    /// them to [`get_det_boxes`](crate::utils::get_det_boxes) or
+    /// [`post_process`](crate::utils::post_process)
+    pub fn mask_scores<B: Backend>(
+        &self,
+        text_map: Tensor<B, 4>,
+        link_map: Tensor<B, 4>,
+        transform: &ImageTransform,
+    ) -> (Tensor<B, 4>, Tensor<B, 4>) {
+        if self.include.is_empty() && self.exclude.is_none() {
+            return (text_map, link_map);
+        }
+        let [_, height, width, _] = text_map.shape().dims::<4>();
+        let mask = self.heatmap_mask::<B>(transform, (height, width), &text_map.device());
+        (text_map * mask.clone(), link_map * mask)
+    }
+}
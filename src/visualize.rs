@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use burn::config::Config;
+use image::{DynamicImage, Rgb, RgbImage};
+use imageproc::{
+    drawing::{draw_filled_rect_mut, draw_hollow_polygon_mut},
+    rect::Rect,
+};
+
+use crate::{
+    heatmap::{ScoreImage, ScoreImages},
+    image_util::color_map::jet,
+    utils::Detection,
+};
+
+/// Colors evenly spaced over `[0, 1]` of matplotlib's viridis
+const VIRIDIS: [[u8; 3]; 9] = [
+    [0x44, 0x01, 0x54],
+    [0x47, 0x2d, 0x7b],
+    [0x3b, 0x52, 0x8b],
+    [0x2c, 0x72, 0x8e],
+    [0x21, 0x90, 0x8c],
+    [0x27, 0xad, 0x81],
+    [0x5d, 0xc8, 0x63],
+    [0xaa, 0xdc, 0x32],
+    [0xfd, 0xe7, 0x25],
+];
+
+/// Colors evenly spaced over `[0, 1]` of matplotlib's magma
+const MAGMA: [[u8; 3]; 9] = [
+    [0x00, 0x00, 0x04],
+    [0x1d, 0x11, 0x47],
+    [0x51, 0x12, 0x7c],
+    [0x82, 0x26, 0x81],
+    [0xb6, 0x36, 0x79],
+    [0xe6, 0x51, 0x64],
+    [0xfb, 0x88, 0x61],
+    [0xfe, 0xc2, 0x87],
+    [0xfc, 0xfd, 0xbf],
+];
+
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+
+/// Glyph of the label font, one row per byte with the leftmost pixel in the highest used bit
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        _ => [0; 5],
+    }
+}
+
+/// Colormap for scores in `[0, 1]`
+#[derive(Config, Debug, Copy, PartialEq, Eq)]
+pub enum ColorMap {
+    /// Same colors as [`float_to_color_map`](crate::image_util::float_to_color_map)
+    Jet,
+    Viridis,
+    Magma,
+    Grayscale,
+}
+
+impl ColorMap {
+    /// Color of `score`, clamped to `[0, 1]`
+    pub fn color(self, score: f32) -> Rgb<u8> {
+        let score = score.clamp(0.0, 1.0);
+        match self {
+            ColorMap::Jet => {
+                let i = (score * 255.0) as usize;
+                Rgb([jet::R[i], jet::G[i], jet::B[i]].map(|c| (c * 255.0) as u8))
+            }
+            ColorMap::Viridis => interpolate(&VIRIDIS, score),
+            ColorMap::Magma => interpolate(&MAGMA, score),
+            ColorMap::Grayscale => Rgb([(score * 255.0).round() as u8; 3]),
+        }
+    }
+}
+
+/// Linear interpolation between colors evenly spaced over `[0, 1]`
+fn interpolate(colors: &[[u8; 3]], score: f32) -> Rgb<u8> {
+    let position = score * (colors.len() - 1) as f32;
+    let i = (position as usize).min(colors.len() - 2);
+    let t = position - i as f32;
+    let (a, b) = (colors[i], colors[i + 1]);
+    Rgb([0, 1, 2].map(|c| (a[c] as f32 * (1.0 - t) + b[c] as f32 * t).round() as u8))
+}
+
+#[derive(Config, Debug)]
+pub struct OverlayConfig {
+    #[config(default = "ColorMap::Jet")]
+    colormap: ColorMap,
+    /// Opacity of the colormap over the image
+    #[config(default = 0.5)]
+    alpha: f32,
+    #[config(default = "[255, 0, 0]")]
+    box_color: [u8; 3],
+    /// Label each box with its id and score
+    #[config(default = true)]
+    labels: bool,
+    /// Size of a font pixel of the labels, in image pixels
+    #[config(default = 2)]
+    label_scale: u32,
+    /// Space between panels
+    #[config(default = 8)]
+    gap: u32,
+}
+
+/// Color `scores` with `colormap`
+pub fn colorize(scores: &ScoreImage, colormap: ColorMap) -> RgbImage {
+    RgbImage::from_par_fn(scores.width(), scores.height(), |x, y| {
+        colormap.color(scores.get_pixel(x, y)[0])
+    })
+}
+
+/// Blend `scores` colored with `colormap` over `image`, with an opacity of `alpha`. The scores
+/// must have the size of the image, e.g. from [`score_images`](crate::heatmap::score_images).
+pub fn overlay(image: &RgbImage, scores: &ScoreImage, colormap: ColorMap, alpha: f32) -> RgbImage {
+    assert_eq!(
+        image.dimensions(),
+        scores.dimensions(),
+        "Scores must have the size of the image"
+    );
+    RgbImage::from_par_fn(image.width(), image.height(), |x, y| {
+        let color = colormap.color(scores.get_pixel(x, y)[0]);
+        let pixel = image.get_pixel(x, y);
+        Rgb([0, 1, 2]
+            .map(|c| (pixel[c] as f32 * (1.0 - alpha) + color[c] as f32 * alpha).round() as u8))
+    })
+}
+
+/// Draw the boxes of `detections` in original image coordinates, e.g. after
+/// [`adjust_coordinates`](crate::utils::adjust_coordinates). With `labels`, each box is tagged
+/// with `id:score` above its top left corner, in a font scaled by `label_scale`.
+pub fn draw_detections(
+    image: &mut RgbImage,
+    detections: &HashMap<u32, Detection>,
+    color: Rgb<u8>,
+    labels: bool,
+    label_scale: u32,
+) {
+    // Sorted so overlapping labels are drawn in the same order every time
+    let mut ids: Vec<_> = detections.keys().copied().collect();
+    ids.sort_unstable();
+    for id in ids {
+        let bbox = &detections[&id].bbox;
+        draw_hollow_polygon_mut(image, bbox, color);
+        if labels {
+            let left = bbox.iter().map(|p| p.x).fold(f32::INFINITY, f32::min);
+            let top = bbox.iter().map(|p| p.y).fold(f32::INFINITY, f32::min);
+            let text = format!("{id}:{:.2}", detections[&id].confidence.score);
+            let label_height = (GLYPH_HEIGHT + 2) * label_scale.max(1);
+            let y = top as i32 - label_height as i32;
+            draw_label(image, &text, (left as i32, y.max(0)), color, label_scale);
+        }
+    }
+}
+
+/// Draw `text` in white on a `background` box with its top left corner at `(x, y)`. Only
+/// digits and `.:-#` are supported, other characters are left blank.
+pub fn draw_label(
+    image: &mut RgbImage,
+    text: &str,
+    (x, y): (i32, i32),
+    background: Rgb<u8>,
+    scale: u32,
+) {
+    let scale = scale.max(1);
+    let advance = (GLYPH_WIDTH + 1) * scale;
+    let width = advance * text.chars().count() as u32 + scale;
+    let height = (GLYPH_HEIGHT + 2) * scale;
+    draw_filled_rect_mut(image, Rect::at(x, y).of_size(width, height), background);
+
+    for (i, c) in text.chars().enumerate() {
+        let left = x + (scale + i as u32 * advance) as i32;
+        for (row, bits) in glyph(c).into_iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+                let top = y + ((row as u32 + 1) * scale) as i32;
+                let pixel = Rect::at(left + (column * scale) as i32, top).of_size(scale, scale);
+                draw_filled_rect_mut(image, pixel, Rgb([255, 255, 255]));
+            }
+        }
+    }
+}
+
+/// Place `panels` next to each other from left to right, `gap` pixels apart, on black
+pub fn side_by_side(panels: &[RgbImage], gap: u32) -> RgbImage {
+    let width =
+        panels.iter().map(|p| p.width()).sum::<u32>() + gap * panels.len().saturating_sub(1) as u32;
+    let height = panels.iter().map(|p| p.height()).max().unwrap_or(0);
+    let mut out = RgbImage::new(width, height);
+    let mut x = 0;
+    for panel in panels {
+        image::imageops::replace(&mut out, panel, x as i64, 0);
+        x += panel.width() + gap;
+    }
+    out
+}
+
+/// Debug view of the detections of an image: the image with the boxes, the region scores over the
+/// image and the link scores over the image, side by side. The boxes are drawn on every panel, so
+/// they can be compared against both score maps.
+pub fn visualize(
+    image: &DynamicImage,
+    scores: &ScoreImages,
+    detections: &HashMap<u32, Detection>,
+    config: &OverlayConfig,
+) -> RgbImage {
+    let image = image.to_rgb8();
+    let mut panels = [
+        image.clone(),
+        overlay(&image, &scores.text, config.colormap, config.alpha),
+        overlay(&image, &scores.link, config.colormap, config.alpha),
+    ];
+    for panel in &mut panels {
+        draw_detections(
+            panel,
+            detections,
+            Rgb(config.box_color),
+            config.labels,
+            config.label_scale,
+        );
+    }
+    side_by_side(&panels, config.gap)
+}
@@ -0,0 +1,22 @@
+use burn::{backend::NdArray, tensor::Tensor};
+use craft_burn::{npy::load_npz, utils::get_det_boxes};
+
+/// Run the post-processing on synthetic network outputs, stored as `y` like `craft-test
+/// --dump-maps` does, and compare the boxes with the ones stored alongside them
+#[test]
+fn replay_score_maps() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/score_maps.npz");
+    let arrays = load_npz(path).unwrap();
+    let y: Tensor<NdArray, 4> = arrays["y"].to_tensor(&Default::default());
+
+    let boxes = get_det_boxes(y.clone().narrow(3, 0, 1), y.narrow(3, 1, 1), 0.7, 0.4, 0.4);
+
+    let ids = &arrays["ids"];
+    let expected = &arrays["boxes"];
+    assert_eq!(boxes.len(), ids.data.len());
+    for (id, corners) in ids.data.iter().zip(expected.data.chunks(8)) {
+        let bbox = boxes[&(*id as u32)];
+        let bbox: Vec<_> = bbox.iter().flat_map(|p| [p.x, p.y]).collect();
+        assert_eq!(bbox, corners);
+    }
+}